@@ -0,0 +1,196 @@
+import base64
+import hashlib
+import logging
+import os
+import threading
+from contextlib import contextmanager
+from dataclasses import dataclass
+from datetime import datetime, timedelta, timezone
+from typing import Optional, Union
+from urllib.parse import urlsplit
+
+import requests
+from lxml import etree
+from onvif import ONVIFCamera
+from requests.auth import HTTPDigestAuth
+from zeep.transports import Transport
+
+from app.error import onvif_errors
+from app.logs import SoapLoggingPlugin
+from app.secret import Secret
+from app.soap import post_soap
+
+logger = logging.getLogger(__name__)
+
+WSSE_NAMESPACE = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-secext-1.0.xsd"
+WSU_NAMESPACE = "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-wssecurity-utility-1.0.xsd"
+PASSWORD_DIGEST_TYPE = (
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-username-token-profile-1.0#PasswordDigest"
+)
+BASE64_ENCODING_TYPE = (
+    "http://docs.oasis-open.org/wss/2004/01/oasis-200401-wss-soap-message-security-1.0#Base64Binary"
+)
+
+
+@dataclass
+class Credentials:
+    username: str
+    password: Union[Secret, str]
+
+    def __post_init__(self):
+        if not isinstance(self.password, Secret):
+            self.password = Secret(self.password)
+
+
+def password_digest(nonce: bytes, created: str, password: str) -> str:
+    """
+    Compute the WS-Security PasswordDigest: Base64(SHA1(nonce + created + password)).
+    """
+    digest = hashlib.sha1(nonce + created.encode("utf-8") + password.encode("utf-8")).digest()
+    return base64.b64encode(digest).decode("ascii")
+
+
+def format_created(timestamp: datetime) -> str:
+    return timestamp.astimezone(timezone.utc).strftime("%Y-%m-%dT%H:%M:%SZ")
+
+
+def security_header(credentials: Credentials, nonce: bytes, created: str) -> etree._Element:
+    security = etree.Element(f"{{{WSSE_NAMESPACE}}}Security", nsmap={"wsse": WSSE_NAMESPACE, "wsu": WSU_NAMESPACE})
+    token = etree.SubElement(security, f"{{{WSSE_NAMESPACE}}}UsernameToken")
+    etree.SubElement(token, f"{{{WSSE_NAMESPACE}}}Username").text = credentials.username
+
+    password = etree.SubElement(token, f"{{{WSSE_NAMESPACE}}}Password", Type=PASSWORD_DIGEST_TYPE)
+    password.text = password_digest(nonce, created, credentials.password.reveal())
+
+    encoded_nonce = etree.SubElement(token, f"{{{WSSE_NAMESPACE}}}Nonce", EncodingType=BASE64_ENCODING_TYPE)
+    encoded_nonce.text = base64.b64encode(nonce).decode("ascii")
+
+    etree.SubElement(token, f"{{{WSU_NAMESPACE}}}Created").text = created
+    return security
+
+
+class UsernameToken:
+    """
+    zeep WS-Security plugin adding a digest UsernameToken with a fresh nonce to every request.
+    `clock_offset` is the camera clock minus the host clock, so `Created` falls within the camera's validity window.
+    """
+
+    def __init__(self, credentials: Credentials, clock_offset: Optional[timedelta] = None):
+        self.credentials = credentials
+        self.clock_offset = clock_offset or timedelta()
+
+    def apply(self, envelope, headers):
+        soap_namespace = etree.QName(envelope).namespace
+        header = envelope.find(f"{{{soap_namespace}}}Header")
+        if header is None:
+            header = etree.Element(f"{{{soap_namespace}}}Header")
+            envelope.insert(0, header)
+
+        created = format_created(datetime.now(timezone.utc) + self.clock_offset)
+        header.append(security_header(self.credentials, nonce=os.urandom(16), created=created))
+        return envelope, headers
+
+    def verify(self, envelope):
+        return envelope
+
+
+class DigestFallbackAuth(HTTPDigestAuth):
+    """
+    Answers HTTP Digest challenges for cameras that guard their ONVIF endpoints with HTTP authentication
+    instead of (or on top of) WS-Security. Requests only carry the UsernameToken until an endpoint answers
+    401 with a Digest challenge; the same envelope is then sent again with a digest `Authorization`,
+    and later requests to that endpoint carry one right away.
+    """
+
+    def __init__(self, credentials: Credentials):
+        super().__init__(credentials.username, credentials.password.reveal())
+        self._digest_endpoints: set[str] = set()
+        self._endpoints_lock = threading.Lock()
+
+    @staticmethod
+    def _endpoint(url: str) -> str:
+        parts = urlsplit(url)
+        return f"{parts.netloc}{parts.path}"
+
+    def __call__(self, request):
+        request = super().__call__(request)
+        with self._endpoints_lock:
+            uses_digest = self._endpoint(request.url) in self._digest_endpoints
+        if not uses_digest:
+            # The challenge of another endpoint of the same camera doesn't mean this one wants digest.
+            request.headers.pop("Authorization", None)
+        return request
+
+    def handle_401(self, response, **kwargs):
+        retried = super().handle_401(response, **kwargs)
+        # A digest retry that is rejected too means wrong credentials, not a missing authentication scheme.
+        if retried is not response and retried.status_code != 401:
+            endpoint = self._endpoint(response.request.url)
+            with self._endpoints_lock:
+                is_new = endpoint not in self._digest_endpoints
+                self._digest_endpoints.add(endpoint)
+            if is_new:
+                logger.info(f"{endpoint} requires HTTP Digest authentication, using it from now on.")
+        return retried
+
+
+class LimitedTransport(Transport):
+    """
+    zeep transport letting at most `limit` SOAP requests to the camera be in flight at once, since some
+    cameras answer concurrent requests with Faults. Others wait for a free slot. The RTSP media path
+    doesn't go through here and is never held up.
+    """
+
+    def __init__(self, limit: int, **kwargs):
+        super().__init__(**kwargs)
+        self._slots = threading.BoundedSemaphore(limit)
+        self._unlimited = threading.local()
+
+    def post(self, address, message, headers):
+        return post_soap(self.send, address, message, headers)
+
+    def send(self, address, message, headers):
+        """Post without the Fault check, for callers that run `post_soap` themselves."""
+        if getattr(self._unlimited, "active", False):
+            return super().post(address, message, headers)
+        with self._slots:
+            return super().post(address, message, headers)
+
+    @contextmanager
+    def long_poll(self):
+        """Send this thread's requests without taking a slot, for calls the camera holds open on purpose."""
+        self._unlimited.active = True
+        try:
+            yield
+        finally:
+            self._unlimited.active = False
+
+
+def connect(
+    host: str, port: Optional[int], credentials: Credentials, max_concurrent_requests: int = 1
+) -> ONVIFCamera:
+    # Every service client of the camera shares this transport, and with it the digest state per endpoint
+    # and the request limit.
+    session = requests.Session()
+    session.auth = DigestFallbackAuth(credentials)
+    # `adjust_time` measures the camera clock offset on connect so digest timestamps are accepted.
+    with onvif_errors(f"connect to {host}"):
+        return ONVIFCamera(
+            host=host,
+            port=port,
+            user=credentials.username,
+            passwd=credentials.password.reveal(),
+            adjust_time=True,
+            transport=LimitedTransport(max_concurrent_requests, session=session),
+        )
+
+
+def create_service(camera: ONVIFCamera, name: str, port_type: Optional[str] = None):
+    """
+    Create an ONVIF service client (e.g. "media", "ptz") that signs every request with our UsernameToken.
+    """
+    service = camera.create_onvif_service(name, portType=port_type)
+    credentials = Credentials(username=camera.user, password=Secret(camera.passwd))
+    service.zeep_client.wsse = UsernameToken(credentials, clock_offset=camera.dt_diff)
+    service.zeep_client.plugins.append(SoapLoggingPlugin())
+    return service
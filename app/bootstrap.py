@@ -0,0 +1,152 @@
+import asyncio
+import json
+import logging
+from concurrent.futures import ThreadPoolExecutor
+from dataclasses import dataclass
+from datetime import datetime
+from typing import Optional
+from urllib.parse import urlparse
+
+from make87_messages.core.header_pb2 import Header
+from make87_messages.text.text_plain_pb2 import PlainText
+
+from app.auth import connect
+from app.config import BootstrapSettings, CameraConfig, CandidateCredentials
+from app.device import get_device_information
+from app.discovery import DiscoveredCamera, discover_devices
+from app.error import AuthError, OnvifError, onvif_errors
+from app.media import create_media_service, get_profiles, get_stream_uri, select_preferred_profile
+from app.rtsp import RtspSettings, inject_rtsp_auth, open_stream
+from app.services import discover_services
+
+logger = logging.getLogger(__name__)
+
+# Cameras probed at the same time.
+PROBE_WORKERS = 8
+
+
+@dataclass
+class BootstrappedCamera:
+    camera: CameraConfig
+    # The same camera as a CAMERAS entry, with the password as the reference it was resolved from.
+    entry: dict
+
+
+def device_xaddr(device: DiscoveredCamera) -> Optional[str]:
+    """The first HTTP(S) device service address a discovered camera advertised."""
+    for xaddr in device.xaddrs:
+        parsed = urlparse(xaddr)
+        if parsed.scheme in ("http", "https") and parsed.hostname:
+            return xaddr
+    return None
+
+
+def _resolve_camera(
+    camera, xaddr: str, candidate: CandidateCredentials, settings: BootstrapSettings, rtsp: RtspSettings
+) -> Optional[BootstrappedCamera]:
+    parsed = urlparse(xaddr)
+    host = parsed.hostname
+    media_service = create_media_service(camera, discover_services(camera))
+    profile = select_preferred_profile(get_profiles(media_service), settings.profile_preference)
+    if profile is None:
+        logger.warning(f"{host} has no profile matching {settings.profile_preference}, skipping it.")
+        return None
+
+    # Only media actually arriving shows which RTP transport gets through to this camera.
+    credentials = candidate.credentials
+    stream_uri = get_stream_uri(media_service, profile.token)
+    stream_uri = inject_rtsp_auth(stream_uri, credentials.username, credentials.password.reveal())
+    with onvif_errors("RTSP stream"), open_stream(stream_uri, rtsp) as opened:
+        transport = opened.transport
+
+    logger.info(
+        f"{host} accepts {credentials.username} ({candidate.password_reference}), "
+        f"streaming profile {profile.name} ({profile.token}) over {transport.upper()}."
+    )
+    config = CameraConfig(
+        id=host,
+        host=host,
+        port=parsed.port,
+        credentials=credentials,
+        profile_preference=settings.profile_preference,
+        rtsp_transport=transport,
+    )
+    entry = {
+        "id": config.id,
+        "url": xaddr,
+        "username": credentials.username,
+        "password": candidate.password_reference,
+        "prefer": str(settings.profile_preference),
+        "transport": transport,
+    }
+    return BootstrappedCamera(config, entry)
+
+
+def probe_device(xaddr: str, settings: BootstrapSettings, rtsp: RtspSettings) -> Optional[BootstrappedCamera]:
+    """Try the candidate credentials on one camera in turn, and resolve its configuration with the first that works."""
+    parsed = urlparse(xaddr)
+    for candidate in settings.candidates:
+        username = candidate.credentials.username
+        try:
+            camera = connect(parsed.hostname, parsed.port, candidate.credentials)
+            info = get_device_information(camera)
+        except AuthError:
+            logger.info(f"{parsed.hostname} rejected {username} ({candidate.password_reference}).")
+            continue
+
+        logger.info(f"{parsed.hostname} is a {info.manufacturer} {info.model}.")
+        return _resolve_camera(camera, xaddr, candidate, settings, rtsp)
+
+    logger.warning(f"{parsed.hostname} accepted none of the candidate credentials.")
+    return None
+
+
+def _probe(xaddr: str, settings: BootstrapSettings, rtsp: RtspSettings) -> Optional[BootstrappedCamera]:
+    try:
+        return probe_device(xaddr, settings, rtsp)
+    except OnvifError as e:
+        logger.warning(f"Could not probe {urlparse(xaddr).hostname}: {e}")
+        return None
+
+
+async def bootstrap_cameras(
+    settings: BootstrapSettings, rtsp: RtspSettings, discovery_timeout: float
+) -> list[BootstrappedCamera]:
+    """
+    Discover the cameras on the network and probe them in parallel, all within `settings.timeout`.
+    Cameras still being probed at the deadline are left out.
+    """
+    loop = asyncio.get_running_loop()
+    deadline = loop.time() + settings.timeout
+    # A probe of an unreachable camera blocks in socket calls; a pool of its own can be abandoned at the deadline.
+    executor = ThreadPoolExecutor(max_workers=PROBE_WORKERS, thread_name_prefix="bootstrap")
+    try:
+        devices = await loop.run_in_executor(executor, discover_devices, min(discovery_timeout, settings.timeout))
+        xaddrs = {xaddr for xaddr in map(device_xaddr, devices) if xaddr is not None}
+        logger.info(f"Bootstrap: probing {len(xaddrs)} discovered camera(s).")
+        if not xaddrs:
+            return []
+
+        probes = [loop.run_in_executor(executor, _probe, xaddr, settings, rtsp) for xaddr in sorted(xaddrs)]
+        done, pending = await asyncio.wait(probes, timeout=max(0.0, deadline - loop.time()))
+        if pending:
+            logger.warning(f"{len(pending)} camera(s) could not be probed within {settings.timeout:g}s, skipping them.")
+    finally:
+        executor.shutdown(wait=False, cancel_futures=True)
+
+    cameras = {}
+    for probe in done:
+        if probe.exception() is not None:
+            logger.error("Probing a camera failed", exc_info=probe.exception())
+        elif probe.result() is not None:
+            cameras.setdefault(probe.result().camera.id, probe.result())
+    return [cameras[camera_id] for camera_id in sorted(cameras)]
+
+
+def publish_bootstrap_config(topic, cameras: list[BootstrappedCamera]):
+    """Publish (and log) the resolved cameras as a CAMERAS value, so the setup can be pinned in the config."""
+    body = json.dumps([camera.entry for camera in cameras])
+    logger.info(f"Bootstrapped configuration: CAMERAS={body}")
+    header = Header(entity_path="/bootstrap")
+    header.timestamp.FromDatetime(datetime.now())
+    topic.publish(PlainText(header=header, body=body))
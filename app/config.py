@@ -0,0 +1,360 @@
+import json
+import os
+from dataclasses import dataclass, field
+from typing import Callable, Optional, TypeVar
+from urllib.parse import urlparse
+
+import make87
+
+from app.auth import Credentials
+from app.error import ConfigError
+from app.frame_queue import OVERFLOW_POLICIES
+from app.media import ProfilePreference, parse_profile_preference
+from app.retry import BackoffPolicy
+from app.rtsp import RTSP_TRANSPORTS, RtspSettings
+from app.secret import Secret, UnresolvedSecret, is_reference, resolve_secret
+from app.time_sync import TIME_SYNC_MODES, TimeSyncPolicy
+
+T = TypeVar("T")
+
+LOG_LEVELS = ("DEBUG", "INFO", "WARNING", "ERROR", "CRITICAL")
+
+
+def parse_url(url):
+    parsed = urlparse(url)
+    protocol = parsed.scheme
+    ip = parsed.hostname
+    port = parsed.port  # Will be None if not specified in the URL
+    url_suffix = parsed.path  # The part after the IP and port
+
+    return protocol, ip, port, url_suffix
+
+
+@dataclass
+class CameraConfig:
+    # Namespaces everything published for this camera, as `/camera/<id>/...` entity paths.
+    id: str
+    host: str
+    port: Optional[int]
+    credentials: Credentials
+    # Pins a profile by its index; `None` selects by `profile_preference`.
+    profile_index: Optional[int] = None
+    profile_preference: ProfilePreference = field(default_factory=ProfilePreference)
+    # `None` selects the lowest-resolution video profile other than the main one.
+    sub_profile_index: Optional[int] = None
+    # Overrides RTSP_TRANSPORT for this camera.
+    rtsp_transport: Optional[str] = None
+    # Overrides ONVIF_MAX_CONCURRENT_REQUESTS for this camera.
+    max_concurrent_requests: Optional[int] = None
+
+    @property
+    def entity_path(self) -> str:
+        return f"/camera/{self.id}"
+
+    @property
+    def address(self) -> str:
+        return f"{self.host}:{self.port}" if self.port else self.host
+
+
+@dataclass
+class CandidateCredentials:
+    """Credentials the bootstrap mode tries on discovered cameras, with the reference their password came from."""
+
+    credentials: Credentials
+    password_reference: str
+
+
+@dataclass
+class BootstrapSettings:
+    candidates: list[CandidateCredentials]
+    # Seconds the whole discovery and probing may take; cameras not probed by then are skipped.
+    timeout: float = 60.0
+    profile_preference: ProfilePreference = field(default_factory=ProfilePreference)
+
+
+@dataclass
+class DriverConfig:
+    cameras: list[CameraConfig]
+    discovery_timeout: float = 3.0
+    snapshot_interval: float = 0.0
+    ptz_command_timeout: float = 1.0
+    ptz_command_interval: float = 0.1
+    ptz_status_interval: float = 1.0
+    health_interval: float = 5.0
+    shutdown_timeout: float = 5.0
+    reboot_wait: float = 60.0
+    allow_factory_default: bool = False
+    # SOAP requests in flight per camera at once; fragile cameras fault on concurrent ones.
+    max_concurrent_requests: int = 1
+    backoff: BackoffPolicy = field(default_factory=BackoffPolicy)
+    time_sync: TimeSyncPolicy = field(default_factory=TimeSyncPolicy)
+    rtsp: RtspSettings = field(default_factory=RtspSettings)
+    # Publish rate cap of the sub stream; `rtsp.publish_fps` caps the main stream.
+    sub_publish_fps: Optional[float] = None
+    # Serve Prometheus metrics on `metrics_port`; no port is opened otherwise.
+    metrics_enabled: bool = False
+    metrics_port: int = 9102
+    # Set when the cameras are to be discovered at startup instead of configured; `cameras` is empty until then.
+    bootstrap: Optional[BootstrapSettings] = None
+
+
+def _optional(name: str, default: str, decode: Callable[[str], T]) -> T:
+    value = make87.get_config_value(name, default=default)
+    try:
+        return decode(value)
+    except ValueError as e:
+        raise ConfigError(f"Invalid value {value!r} for {name}: {e}") from e
+
+
+def _required(name: str) -> str:
+    value = make87.get_config_value(name, default="")
+    if not value:
+        raise ConfigError(f"Missing required config value {name}.")
+    return value
+
+
+def _positive_float(value) -> float:
+    number = float(value)
+    if number <= 0:
+        raise ValueError("must be greater than 0")
+    return number
+
+
+def _positive_int(value) -> int:
+    number = int(value)
+    if number <= 0:
+        raise ValueError("must be greater than 0")
+    return number
+
+
+def _frame_rate(value: str) -> Optional[float]:
+    return _positive_float(value) if value not in ("", None) else None
+
+
+def _port(value) -> int:
+    port = int(value)
+    if not 0 < port < 65536:
+        raise ValueError("must be between 1 and 65535")
+    return port
+
+
+def _overflow_policy(value: str) -> str:
+    policy = value.lower()
+    if policy not in OVERFLOW_POLICIES:
+        raise ValueError(f"expected one of {', '.join(OVERFLOW_POLICIES)}")
+    return policy
+
+
+def _rtsp_transport(value: str) -> str:
+    transport = value.lower()
+    if transport not in RTSP_TRANSPORTS:
+        raise ValueError(f"expected one of {', '.join(RTSP_TRANSPORTS)}")
+    return transport
+
+
+def _time_sync_mode(value: str) -> str:
+    mode = value.lower()
+    if mode not in TIME_SYNC_MODES:
+        raise ValueError(f"expected one of {', '.join(TIME_SYNC_MODES)}")
+    return mode
+
+
+def _boolean(value: str) -> bool:
+    if value.lower() in ("true", "1", "yes"):
+        return True
+    if value.lower() in ("false", "0", "no"):
+        return False
+    raise ValueError("expected true or false")
+
+
+def _comma_separated(value: str) -> list[str]:
+    return [item.strip() for item in value.split(",") if item.strip()]
+
+
+def _parse_profile_index(value) -> Optional[int]:
+    if value in ("", None):
+        return None
+    index = int(value)
+    if index < 0:
+        raise ValueError("must be 0 or greater")
+    return index
+
+
+def _password(value: str, setting: str) -> Secret:
+    """The password a `secret:NAME` / `env:NAME` reference points to; passwords never come from plain config."""
+    if not is_reference(value):
+        raise ConfigError(
+            f"{setting} must reference a secret (secret:NAME) or environment variable (env:NAME)"
+            " instead of containing the password."
+        )
+    try:
+        return resolve_secret(value)
+    except UnresolvedSecret as e:
+        raise ConfigError(f"Could not resolve {setting}: {e}") from None
+
+
+def _camera_from_url(
+    camera_id: Optional[str],
+    url: str,
+    credentials: Credentials,
+    profile_index,
+    sub_profile_index=None,
+    profile_preference: Optional[ProfilePreference] = None,
+    rtsp_transport: Optional[str] = None,
+    max_concurrent_requests=None,
+) -> CameraConfig:
+    protocol, ip, port, url_suffix = parse_url(url)
+    if not ip:
+        raise ConfigError(f"Camera URL {url!r} has no host.")
+    return CameraConfig(
+        id=camera_id or ip,
+        host=ip,
+        port=port,
+        credentials=credentials,
+        profile_index=_parse_profile_index(profile_index),
+        profile_preference=profile_preference or ProfilePreference(),
+        sub_profile_index=_parse_profile_index(sub_profile_index),
+        rtsp_transport=_rtsp_transport(rtsp_transport) if rtsp_transport else None,
+        max_concurrent_requests=_positive_int(max_concurrent_requests) if max_concurrent_requests is not None else None,
+    )
+
+
+def _cameras_from_json(value: str, profile_preference: ProfilePreference) -> list[CameraConfig]:
+    try:
+        entries = json.loads(value)
+    except json.JSONDecodeError as e:
+        raise ConfigError(f"CAMERAS is not valid JSON: {e}") from e
+    if not isinstance(entries, list) or not entries:
+        raise ConfigError("CAMERAS must be a non-empty JSON list.")
+
+    cameras = []
+    for index, entry in enumerate(entries):
+        if not isinstance(entry, dict):
+            raise ConfigError(f"CAMERAS[{index}] must be a JSON object.")
+        missing = [key for key in ("url", "username", "password") if not entry.get(key)]
+        if missing:
+            raise ConfigError(f"CAMERAS[{index}] is missing {', '.join(missing)}.")
+        password = _password(str(entry["password"]), f"CAMERAS[{index}].password")
+        try:
+            camera = _camera_from_url(
+                entry.get("id"),
+                entry["url"],
+                Credentials(username=entry["username"], password=password),
+                entry.get("profile_index"),
+                entry.get("sub_profile_index"),
+                parse_profile_preference(str(entry["prefer"])) if entry.get("prefer") else profile_preference,
+                entry.get("transport"),
+                entry.get("max_concurrent_requests"),
+            )
+        except ValueError as e:
+            raise ConfigError(f"CAMERAS[{index}] is invalid: {e}") from e
+        cameras.append(camera)
+
+    ids = [camera.id for camera in cameras]
+    duplicates = {camera_id for camera_id in ids if ids.count(camera_id) > 1}
+    if duplicates:
+        raise ConfigError(f"Duplicate camera ids in CAMERAS: {', '.join(sorted(duplicates))}")
+    return cameras
+
+
+def _candidates_from_json(value: str) -> list[CandidateCredentials]:
+    try:
+        entries = json.loads(value)
+    except json.JSONDecodeError as e:
+        raise ConfigError(f"BOOTSTRAP_CREDENTIALS is not valid JSON: {e}") from e
+    if not isinstance(entries, list) or not entries:
+        raise ConfigError("BOOTSTRAP_CREDENTIALS must be a non-empty JSON list.")
+
+    candidates = []
+    for index, entry in enumerate(entries):
+        if not isinstance(entry, dict) or not entry.get("username") or not entry.get("password"):
+            raise ConfigError(f"BOOTSTRAP_CREDENTIALS[{index}] must be an object with a username and password.")
+        reference = str(entry["password"])
+        password = _password(reference, f"BOOTSTRAP_CREDENTIALS[{index}].password")
+        candidates.append(CandidateCredentials(Credentials(username=entry["username"], password=password), reference))
+    return candidates
+
+
+def _camera_from_peripheral(profile_preference: ProfilePreference) -> CameraConfig:
+    try:
+        onvif_url = make87.resolve_peripheral_name("ONVIF_DEVICE")
+    except Exception as e:
+        raise ConfigError(f"Could not resolve the ONVIF_DEVICE peripheral: {e}") from e
+
+    return _camera_from_url(
+        camera_id=None,
+        url=onvif_url,
+        credentials=Credentials(
+            username=_required("ONVIF_USERNAME"), password=_password(_required("ONVIF_PASSWORD"), "ONVIF_PASSWORD")
+        ),
+        profile_index=_optional("PROFILE_INDEX", default="", decode=_parse_profile_index),
+        sub_profile_index=_optional("SUB_PROFILE_INDEX", default="", decode=_parse_profile_index),
+        profile_preference=profile_preference,
+    )
+
+
+def load_log_level() -> str:
+    """LOG_LEVEL from the application config, falling back to the LOG_LEVEL environment variable."""
+    level = make87.get_config_value("LOG_LEVEL", default="") or os.environ.get("LOG_LEVEL", "INFO")
+    if level.upper() not in LOG_LEVELS:
+        raise ConfigError(f"Invalid LOG_LEVEL {level!r}, expected one of {', '.join(LOG_LEVELS)}.")
+    return level.upper()
+
+
+def load_config() -> DriverConfig:
+    """
+    Read and validate the driver configuration from the make87 application config.
+    `CAMERAS` configures several cameras at once; without it the ONVIF_DEVICE peripheral is used.
+    With `BOOTSTRAP` enabled, neither is needed: the cameras are discovered and probed at startup.
+    """
+    profile_preference = _optional("PROFILE_PREFERENCE", default="highest_h264", decode=parse_profile_preference)
+    bootstrap = None
+    cameras_json = make87.get_config_value("CAMERAS", default="")
+    if _optional("BOOTSTRAP", default="false", decode=_boolean):
+        cameras = []
+        bootstrap = BootstrapSettings(
+            candidates=_candidates_from_json(_required("BOOTSTRAP_CREDENTIALS")),
+            timeout=_optional("BOOTSTRAP_TIMEOUT", default="60", decode=_positive_float),
+            profile_preference=profile_preference,
+        )
+    elif cameras_json:
+        cameras = _cameras_from_json(cameras_json, profile_preference)
+    else:
+        cameras = [_camera_from_peripheral(profile_preference)]
+
+    return DriverConfig(
+        cameras=cameras,
+        bootstrap=bootstrap,
+        discovery_timeout=_optional("DISCOVERY_TIMEOUT", default="3", decode=float),
+        snapshot_interval=_optional("SNAPSHOT_INTERVAL", default="0", decode=float),
+        ptz_command_timeout=_optional("PTZ_COMMAND_TIMEOUT", default="1.0", decode=float),
+        ptz_command_interval=_optional("PTZ_COMMAND_INTERVAL", default="0.1", decode=float),
+        ptz_status_interval=_optional("PTZ_STATUS_INTERVAL", default="1.0", decode=float),
+        health_interval=_optional("HEALTH_INTERVAL", default="5", decode=_positive_float),
+        shutdown_timeout=_optional("SHUTDOWN_TIMEOUT", default="5", decode=_positive_float),
+        reboot_wait=_optional("REBOOT_WAIT", default="60", decode=float),
+        allow_factory_default=_optional("ALLOW_FACTORY_DEFAULT", default="false", decode=_boolean),
+        max_concurrent_requests=_optional("ONVIF_MAX_CONCURRENT_REQUESTS", default="1", decode=_positive_int),
+        backoff=BackoffPolicy(
+            base_delay=_optional("RECONNECT_BASE_DELAY", default="1.0", decode=float),
+            max_delay=_optional("RECONNECT_MAX_DELAY", default="30.0", decode=float),
+            max_attempts=_optional("RECONNECT_MAX_ATTEMPTS", default="0", decode=int),
+            jitter=_optional("RECONNECT_JITTER", default="0.2", decode=float),
+        ),
+        rtsp=RtspSettings(
+            transport=_optional("RTSP_TRANSPORT", default="auto", decode=_rtsp_transport),
+            media_timeout=_optional("RTSP_MEDIA_TIMEOUT", default="5", decode=_positive_float),
+            queue_capacity=_optional("FRAME_QUEUE_CAPACITY", default="60", decode=_positive_int),
+            overflow_policy=_optional("FRAME_QUEUE_POLICY", default="drop_oldest", decode=_overflow_policy),
+            stall_timeout=_optional("STREAM_STALL_TIMEOUT", default="15", decode=_positive_float),
+            publish_fps=_optional("PUBLISH_FPS", default="", decode=_frame_rate),
+        ),
+        sub_publish_fps=_optional("SUB_PUBLISH_FPS", default="", decode=_frame_rate),
+        metrics_enabled=_optional("METRICS_ENABLED", default="false", decode=_boolean),
+        metrics_port=_optional("METRICS_PORT", default="9102", decode=_port),
+        time_sync=TimeSyncPolicy(
+            mode=_optional("TIME_SYNC", default="off", decode=_time_sync_mode),
+            max_skew=_optional("TIME_SYNC_MAX_SKEW", default="2", decode=float),
+            ntp_servers=_optional("TIME_SYNC_NTP_SERVERS", default="", decode=_comma_separated),
+        ),
+    )
@@ -0,0 +1,66 @@
+import json
+import logging
+import threading
+from datetime import datetime
+from enum import Enum
+from typing import Callable, Optional
+
+from make87_messages.core.header_pb2 import Header
+from make87_messages.text.text_plain_pb2 import PlainText
+
+logger = logging.getLogger(__name__)
+
+
+class ConnectionState(str, Enum):
+    # Connecting and making the first authenticated requests, where rejected credentials show.
+    AUTHENTICATING = "authenticating"
+    # Looking up the services and media profiles and setting up the RTSP stream, until the first frame arrives.
+    DISCOVERING = "discovering"
+    STREAMING = "streaming"
+    RECONNECTING = "reconnecting"
+    # Given up, e.g. after the camera rejected the credentials. Final.
+    FAILED = "failed"
+
+
+TRANSITIONS = {
+    None: {ConnectionState.AUTHENTICATING},
+    ConnectionState.AUTHENTICATING: {ConnectionState.DISCOVERING, ConnectionState.RECONNECTING, ConnectionState.FAILED},
+    ConnectionState.DISCOVERING: {ConnectionState.STREAMING, ConnectionState.RECONNECTING, ConnectionState.FAILED},
+    ConnectionState.STREAMING: {ConnectionState.RECONNECTING, ConnectionState.FAILED},
+    ConnectionState.RECONNECTING: {ConnectionState.AUTHENTICATING, ConnectionState.FAILED},
+    ConnectionState.FAILED: set(),
+}
+
+
+class ConnectionStatePublisher:
+    """
+    Tracks the camera connection as a state machine and publishes every transition once, with its reason.
+    Setting the current state again is a no-op; transitions outside `TRANSITIONS` are rejected.
+    `on_change` is called with every new state.
+    """
+
+    def __init__(self, topic, entity_path: str, on_change: Optional[Callable[[ConnectionState], None]] = None):
+        self.topic = topic
+        self.entity_path = entity_path
+        self.on_change = on_change
+        self.state = None
+        self._lock = threading.Lock()
+
+    def set(self, state: ConnectionState, reason: str = ""):
+        with self._lock:
+            previous = self.state
+            if state == previous:
+                return
+            previous_value = previous.value if previous else None
+            if state not in TRANSITIONS[previous]:
+                logger.warning(f"Ignoring connection state transition {previous_value} -> {state.value}.")
+                return
+            self.state = state
+
+        if self.on_change is not None:
+            self.on_change(state)
+        logger.info(f"Connection state: {state.value} {reason}".rstrip())
+        header = Header(entity_path=self.entity_path)
+        header.timestamp.FromDatetime(datetime.now())
+        body = {"state": state.value, "previous": previous_value, "reason": reason}
+        self.topic.publish(PlainText(header=header, body=json.dumps(body)))
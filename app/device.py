@@ -0,0 +1,217 @@
+import json
+import logging
+import threading
+from dataclasses import dataclass, field
+from typing import Callable, Optional
+
+from make87_messages.text.text_plain_pb2 import PlainText
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, onvif_errors
+from app.logs import camera_context
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+# GetCapabilities categories and the WSDL namespaces of the services they describe.
+CAPABILITY_NAMESPACES = {
+    "Device": "http://www.onvif.org/ver10/device/wsdl",
+    "Media": "http://www.onvif.org/ver10/media/wsdl",
+    "PTZ": "http://www.onvif.org/ver20/ptz/wsdl",
+    "Events": "http://www.onvif.org/ver10/events/wsdl",
+    "Imaging": "http://www.onvif.org/ver20/imaging/wsdl",
+}
+
+
+@dataclass
+class DeviceInformation:
+    manufacturer: str
+    model: str
+    firmware_version: str
+    serial_number: str
+    hardware_id: str
+
+
+def get_device_information(camera: ONVIFCamera) -> DeviceInformation:
+    """
+    Query the device management service for the camera identity.
+    A camera rejecting the request raises `SoapError`, or `AuthError` for bad credentials.
+    """
+    with onvif_errors("create device service"):
+        device_service = create_service(camera, "devicemgmt")
+    info = call(device_service, "GetDeviceInformation")
+
+    return DeviceInformation(
+        manufacturer=info.Manufacturer,
+        model=info.Model,
+        firmware_version=info.FirmwareVersion,
+        serial_number=info.SerialNumber,
+        hardware_id=info.HardwareId,
+    )
+
+
+@dataclass
+class NetworkInterface:
+    token: str
+    enabled: bool
+    name: Optional[str] = None
+    mac: Optional[str] = None
+    ipv4_address: Optional[str] = None
+    ipv4_prefix_length: Optional[int] = None
+    ipv4_dhcp: Optional[bool] = None
+    ipv6_addresses: list[str] = field(default_factory=list)
+    # Off, Auto, Stateful or Stateless
+    ipv6_dhcp: Optional[str] = None
+    speed_mbps: Optional[int] = None
+    # Full or Half
+    duplex: Optional[str] = None
+
+
+def _path(value, *attributes):
+    """Follow `attributes` through optional response elements, `None` as soon as one is missing."""
+    for attribute in attributes:
+        if value is None:
+            return None
+        value = getattr(value, attribute, None)
+    return value
+
+
+def _prefixed_addresses(addresses) -> list[tuple[str, Optional[int]]]:
+    return [(address.Address, address.PrefixLength) for address in addresses or [] if address and address.Address]
+
+
+def parse_network_interface(interface) -> NetworkInterface:
+    ipv4 = _path(interface, "IPv4", "Config")
+    ipv4_dhcp = _path(ipv4, "DHCP")
+    # The address in use: the DHCP lease when DHCP is on, the first manual address otherwise.
+    ipv4_addresses = _prefixed_addresses([_path(ipv4, "FromDHCP")] if ipv4_dhcp else _path(ipv4, "Manual"))
+    ipv4_address, ipv4_prefix_length = ipv4_addresses[0] if ipv4_addresses else (None, None)
+
+    ipv6 = _path(interface, "IPv6", "Config")
+    ipv6_addresses = []
+    for source in ("Manual", "LinkLocal", "FromDHCP", "FromRA"):
+        ipv6_addresses += [address for address, _ in _prefixed_addresses(_path(ipv6, source))]
+
+    # The negotiated link settings, or the configured ones if the camera doesn't report them.
+    link = _path(interface, "Link", "OperSettings") or _path(interface, "Link", "AdminSettings")
+    speed = _path(link, "Speed")
+    return NetworkInterface(
+        token=interface.token,
+        enabled=bool(interface.Enabled),
+        name=_path(interface, "Info", "Name"),
+        mac=_path(interface, "Info", "HwAddress"),
+        ipv4_address=ipv4_address,
+        ipv4_prefix_length=int(ipv4_prefix_length) if ipv4_prefix_length is not None else None,
+        ipv4_dhcp=bool(ipv4_dhcp) if ipv4_dhcp is not None else None,
+        ipv6_addresses=ipv6_addresses,
+        ipv6_dhcp=_path(ipv6, "DHCP"),
+        speed_mbps=int(speed) if speed is not None else None,
+        duplex=_path(link, "Duplex"),
+    )
+
+
+def get_network_interfaces(camera: ONVIFCamera) -> list[NetworkInterface]:
+    with onvif_errors("create device service"):
+        device_service = create_service(camera, "devicemgmt")
+    interfaces = call(device_service, "GetNetworkInterfaces")
+    return [parse_network_interface(interface) for interface in interfaces or []]
+
+
+def get_capabilities(camera: ONVIFCamera) -> dict[str, str]:
+    """
+    The service addresses from the legacy GetCapabilities, keyed by WSDL namespace,
+    for cameras that predate GetServices. Categories the camera doesn't report are left out.
+    """
+    with onvif_errors("create device service"):
+        device_service = create_service(camera, "devicemgmt")
+    capabilities = call(device_service, "GetCapabilities", {"Category": "All"})
+
+    xaddrs = {}
+    for category, namespace in CAPABILITY_NAMESPACES.items():
+        xaddr = _path(capabilities, category, "XAddr")
+        if xaddr:
+            xaddrs[namespace] = xaddr
+    return xaddrs
+
+
+def system_reboot(device_service) -> str:
+    """Reboot the camera. Returns the camera's message, typically the expected downtime."""
+    return call(device_service, "SystemReboot") or ""
+
+
+def set_system_factory_default(device_service, hard: bool):
+    """Reset the camera to factory settings. A hard reset also resets the network configuration."""
+    call(device_service, "SetSystemFactoryDefault", {"FactoryDefault": "Hard" if hard else "Soft"})
+
+
+class MaintenanceController:
+    """
+    Reboots or factory-resets the camera on commands like `{"action": "reboot", "confirm": true}`.
+    Commands without `"confirm": true` are refused, and factory resets only run if explicitly allowed.
+    `on_restart` is called once the camera accepted the command, so the session can end before the camera vanishes.
+    """
+
+    def __init__(self, camera_id: str, allow_factory_default: bool = False):
+        self.camera_id = camera_id
+        self.allow_factory_default = allow_factory_default
+        self.device_service = None
+        self.on_restart: Optional[Callable[[str], None]] = None
+        self._lock = threading.Lock()
+
+    def attach(self, camera: ONVIFCamera, on_restart: Callable[[str], None]):
+        with onvif_errors("create device service"):
+            device_service = create_service(camera, "devicemgmt")
+        with self._lock:
+            self.device_service = device_service
+            self.on_restart = on_restart
+
+    def detach(self):
+        with self._lock:
+            self.device_service = None
+            self.on_restart = None
+
+    def handle_command(self, message: PlainText):
+        # Commands arrive on make87 subscriber threads, outside of the camera's task.
+        with camera_context(self.camera_id):
+            self._handle_command(message)
+
+    def _handle_command(self, message: PlainText):
+        try:
+            command = json.loads(message.body)
+            # Commands without a "camera" field address every camera.
+            if command.get("camera", self.camera_id) != self.camera_id:
+                return
+            action = command.get("action")
+            if action not in ("reboot", "factory_default"):
+                raise ValueError(f"unknown action {action!r}")
+            hard = command.get("hard", False) is True
+            confirmed = command.get("confirm") is True
+        except (ValueError, TypeError, AttributeError) as e:
+            logger.warning(f"Ignoring malformed maintenance command {message.body!r}: {e}")
+            return
+
+        if not confirmed:
+            logger.warning(f"Refusing {action} without \"confirm\": true.")
+            return
+        if action == "factory_default" and not self.allow_factory_default:
+            logger.warning("Refusing factory reset, set ALLOW_FACTORY_DEFAULT to enable it.")
+            return
+
+        with self._lock:
+            if self.device_service is None:
+                logger.warning(f"No camera connected, dropping {action} command.")
+                return
+            try:
+                if action == "reboot":
+                    reason = f"Camera is rebooting: {system_reboot(self.device_service)}".rstrip(": ")
+                else:
+                    set_system_factory_default(self.device_service, hard)
+                    reason = f"Camera is resetting to factory defaults ({'hard' if hard else 'soft'})"
+            except OnvifError as e:
+                logger.error(f"{action} failed: {e}")
+                return
+            on_restart = self.on_restart
+
+        logger.warning(reason)
+        on_restart(reason)
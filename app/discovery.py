@@ -0,0 +1,172 @@
+import logging
+import socket
+import time
+import uuid
+import xml.etree.ElementTree as ET
+from dataclasses import dataclass, field
+from typing import Optional
+from urllib.parse import unquote, urlparse
+
+logger = logging.getLogger(__name__)
+
+MULTICAST_GROUP = "239.255.255.250"
+MULTICAST_PORT = 3702
+ONVIF_SCOPE_HOST = "www.onvif.org"
+# Profile scopes of devices that predate the single-letter names.
+PROFILE_ALIASES = {"Streaming": "S"}
+
+PROBE_TEMPLATE = """<?xml version="1.0" encoding="UTF-8"?>
+<e:Envelope xmlns:e="http://www.w3.org/2003/05/soap-envelope"
+            xmlns:w="http://schemas.xmlsoap.org/ws/2004/08/addressing"
+            xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery"
+            xmlns:dn="http://www.onvif.org/ver10/network/wsdl">
+  <e:Header>
+    <w:MessageID>uuid:{message_id}</w:MessageID>
+    <w:To e:mustUnderstand="true">urn:schemas-xmlsoap-org:ws:2005:04:discovery</w:To>
+    <w:Action e:mustUnderstand="true">http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</w:Action>
+  </e:Header>
+  <e:Body>
+    <d:Probe>
+      <d:Types>dn:NetworkVideoTransmitter</d:Types>
+    </d:Probe>
+  </e:Body>
+</e:Envelope>"""
+
+
+@dataclass
+class CameraScopeInfo:
+    name: Optional[str] = None
+    location: Optional[str] = None
+    hardware: Optional[str] = None
+    # ONVIF profile conformance, e.g. ["S", "T", "G"].
+    profiles: list[str] = field(default_factory=list)
+    # Scope categories without a field of their own (e.g. "type"), and non-ONVIF scopes under "other".
+    extra: dict[str, list[str]] = field(default_factory=dict)
+
+
+@dataclass
+class DiscoveredCamera:
+    endpoint: str
+    xaddrs: list[str] = field(default_factory=list)
+    scopes: list[str] = field(default_factory=list)
+    types: list[str] = field(default_factory=list)
+    scope_info: CameraScopeInfo = field(default_factory=CameraScopeInfo)
+
+
+def parse_scopes(scopes: list[str]) -> CameraScopeInfo:
+    """
+    Extract the well-known `onvif://www.onvif.org/<category>/<value>` scopes into typed fields.
+    Values are URL-decoded; several location scopes (e.g. country and city) are joined.
+    """
+    info = CameraScopeInfo()
+    locations = []
+    for scope in scopes:
+        parsed = urlparse(scope)
+        category, _, value = parsed.path.strip("/").partition("/")
+        if parsed.scheme != "onvif" or parsed.netloc != ONVIF_SCOPE_HOST or not value:
+            info.extra.setdefault("other", []).append(scope)
+            continue
+
+        value = unquote(value)
+        category = category.lower()
+        if category == "name" and info.name is None:
+            info.name = value
+        elif category == "hardware" and info.hardware is None:
+            info.hardware = value
+        elif category == "location":
+            locations.append(value)
+        elif category == "profile":
+            profile = PROFILE_ALIASES.get(value, value)
+            if profile not in info.profiles:
+                info.profiles.append(profile)
+        else:
+            info.extra.setdefault(category, []).append(value)
+
+    if locations:
+        info.location = ", ".join(locations)
+    return info
+
+
+def _local_name(tag: str) -> str:
+    return tag.rsplit("}", 1)[-1]
+
+
+def _find_child(element: ET.Element, name: str):
+    # Cameras disagree on the WS-Addressing namespace version, so match on local names only.
+    for child in element.iter():
+        if _local_name(child.tag) == name:
+            return child
+    return None
+
+
+def _split(element) -> list[str]:
+    if element is None or not element.text:
+        return []
+    return element.text.split()
+
+
+def parse_probe_matches(data: bytes) -> list[DiscoveredCamera]:
+    """
+    Parse a WS-Discovery ProbeMatches response into cameras.
+    Returns an empty list if the payload is not well-formed XML.
+    """
+    try:
+        root = ET.fromstring(data)
+    except ET.ParseError as e:
+        logger.debug(f"Ignoring malformed discovery response: {e}")
+        return []
+
+    cameras = []
+    for match in root.iter():
+        if _local_name(match.tag) != "ProbeMatch":
+            continue
+
+        address = _find_child(match, "Address")
+        if address is None or not address.text:
+            continue
+
+        scopes = _split(_find_child(match, "Scopes"))
+        cameras.append(
+            DiscoveredCamera(
+                endpoint=address.text.strip(),
+                xaddrs=_split(_find_child(match, "XAddrs")),
+                scopes=scopes,
+                types=_split(_find_child(match, "Types")),
+                scope_info=parse_scopes(scopes),
+            )
+        )
+
+    return cameras
+
+
+def discover_devices(timeout: float = 3.0) -> list[DiscoveredCamera]:
+    """
+    Send a WS-Discovery Probe for ONVIF network video transmitters and collect the answers for `timeout` seconds.
+    Cameras answering on several interfaces are merged by their endpoint reference.
+    """
+    probe = PROBE_TEMPLATE.format(message_id=uuid.uuid4()).encode("utf-8")
+    discovered: dict[str, DiscoveredCamera] = {}
+
+    with socket.socket(socket.AF_INET, socket.SOCK_DGRAM, socket.IPPROTO_UDP) as sock:
+        sock.setsockopt(socket.IPPROTO_IP, socket.IP_MULTICAST_TTL, 2)
+        sock.sendto(probe, (MULTICAST_GROUP, MULTICAST_PORT))
+
+        deadline = time.monotonic() + timeout
+        while (remaining := deadline - time.monotonic()) > 0:
+            sock.settimeout(remaining)
+            try:
+                data, sender = sock.recvfrom(65535)
+            except socket.timeout:
+                break
+
+            for camera in parse_probe_matches(data):
+                known = discovered.get(camera.endpoint)
+                if known is None:
+                    logger.debug(f"Discovered {camera.endpoint} from {sender[0]}")
+                    discovered[camera.endpoint] = camera
+                    continue
+                for xaddr in camera.xaddrs:
+                    if xaddr not in known.xaddrs:
+                        known.xaddrs.append(xaddr)
+
+    return list(discovered.values())
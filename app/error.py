@@ -0,0 +1,149 @@
+from contextlib import contextmanager
+from dataclasses import dataclass, field
+from typing import Optional
+
+import av
+import requests
+from onvif.exceptions import ONVIFError
+from zeep.exceptions import Fault, TransportError, XMLParseError, XMLSyntaxError
+
+from app.secret import redact
+
+# ONVIF subcodes (`ter:` namespace) meaning the camera rejected our credentials.
+AUTH_SUBCODES = {"NotAuthorized", "Unauthorized"}
+
+
+class OnvifError(Exception):
+    """Base class for everything that can go wrong while talking to a camera."""
+
+    # Whether retrying the same operation later has a chance of succeeding.
+    retryable = False
+
+    def __str__(self) -> str:
+        # Messages embed camera answers and FFmpeg errors, which can quote credentials (e.g. in an RTSP URL).
+        return redact(super().__str__())
+
+
+class NetworkError(OnvifError):
+    retryable = True
+
+
+class SoapError(OnvifError):
+    """The camera answered with a SOAP Fault."""
+
+
+class AuthError(OnvifError):
+    """The camera rejected our credentials."""
+
+
+class ParseError(OnvifError):
+    """The camera answered with something we could not interpret."""
+
+
+class TopicResolutionError(OnvifError):
+    """A make87 topic could not be resolved."""
+
+
+class OperationTimeout(OnvifError):
+    retryable = True
+
+
+class StreamStalled(NetworkError):
+    """The RTSP session stayed open but stopped delivering frames."""
+
+
+class CameraRestarting(OnvifError):
+    """The camera was told to reboot (or reset) and will be unreachable for a while."""
+
+
+class ConfigError(OnvifError):
+    """The driver configuration is missing or invalid."""
+
+
+@dataclass
+class SoapFault:
+    # Qualified names as sent by the camera, e.g. "env:Sender" and ["ter:NotAuthorized"].
+    code: str
+    reason: str
+    # SOAP 1.2 subcodes from the outermost to the innermost, e.g. ["ter:InvalidArgVal", "ter:NoProfile"].
+    subcodes: list[str] = field(default_factory=list)
+
+    @property
+    def is_auth(self) -> bool:
+        return any(local_name(code) in AUTH_SUBCODES for code in [self.code, *self.subcodes])
+
+    def __str__(self) -> str:
+        codes = "/".join(local_name(code) for code in [self.code, *self.subcodes])
+        return f"{self.reason} ({codes})" if self.reason else codes
+
+
+def local_name(qualified_name: str) -> str:
+    """`ter:NotAuthorized` and `{http://www.onvif.org/ver10/error}NotAuthorized` both become `NotAuthorized`."""
+    return str(qualified_name).rsplit("}", 1)[-1].rsplit(":", 1)[-1].strip()
+
+
+def _unwrap(exc: BaseException) -> BaseException:
+    # onvif-zeep re-raises every service error as ONVIFError from within the handler, so the cause is the context.
+    while isinstance(exc, ONVIFError) and exc.__context__ is not None:
+        exc = exc.__context__
+    return exc
+
+
+def _soap_fault(fault: Fault) -> SoapFault:
+    return SoapFault(
+        code=str(fault.code or ""),
+        reason=fault.message or "",
+        subcodes=[str(code) for code in getattr(fault, "subcodes", None) or []],
+    )
+
+
+def fault_error(fault: SoapFault, operation: str) -> OnvifError:
+    # Bad credentials won't get better by retrying, so they must not look like a transient failure.
+    if fault.is_auth:
+        return AuthError(f"{operation}: {fault}")
+    return SoapError(f"{operation}: {fault}")
+
+
+def translate_error(exc: BaseException, operation: str) -> Optional[OnvifError]:
+    """
+    Map an exception raised by onvif-zeep, zeep or requests onto an `OnvifError`.
+    Returns `None` for exceptions that are not related to camera communication.
+    """
+    cause = _unwrap(exc)
+
+    if isinstance(cause, OnvifError):
+        return cause
+    if isinstance(cause, Fault):
+        return fault_error(_soap_fault(cause), operation)
+    if isinstance(cause, TransportError):
+        # Responses carrying a Fault never get here: `post_soap` raises them before zeep parses the response.
+        if cause.status_code in (401, 403):
+            return AuthError(f"{operation}: HTTP {cause.status_code}")
+        return NetworkError(f"{operation}: HTTP {cause.status_code}")
+    if isinstance(cause, requests.exceptions.Timeout):
+        return OperationTimeout(f"{operation}: {cause}")
+    if isinstance(cause, requests.exceptions.RequestException):
+        return NetworkError(f"{operation}: {cause}")
+    if isinstance(cause, (av.error.HTTPUnauthorizedError, av.error.HTTPForbiddenError)):
+        return AuthError(f"{operation}: {cause}")
+    if isinstance(cause, av.error.FFmpegError):
+        return NetworkError(f"{operation}: {cause}")
+    if isinstance(cause, (XMLParseError, XMLSyntaxError)):
+        return ParseError(f"{operation}: {cause}")
+    if isinstance(cause, ONVIFError):
+        return SoapError(f"{operation}: {cause}")
+    return None
+
+
+@contextmanager
+def onvif_errors(operation: str):
+    """Re-raise camera communication failures inside the block as `OnvifError`."""
+    try:
+        yield
+    except OnvifError:
+        raise
+    except Exception as e:
+        error = translate_error(e, operation)
+        if error is None:
+            raise
+        raise error from e
@@ -0,0 +1,278 @@
+import asyncio
+import json
+import logging
+from dataclasses import asdict, dataclass, field
+from datetime import datetime, timedelta, timezone
+from enum import Enum
+from typing import Optional, Union
+
+from lxml import etree
+from make87_messages.core.header_pb2 import Header
+from make87_messages.text.text_plain_pb2 import PlainText
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, onvif_errors
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+EVENTS_NAMESPACE = "http://www.onvif.org/ver10/events/wsdl"
+
+
+class MotionDetector(str, Enum):
+    # tns1:RuleEngine/CellMotionDetector/Motion, raised by a motion detection rule of the analytics engine.
+    CELL_MOTION = "cell_motion"
+    # tns1:VideoSource/MotionAlarm, raised by the video source itself.
+    MOTION_ALARM = "motion_alarm"
+
+
+@dataclass
+class MotionStarted:
+    detector: MotionDetector
+    video_source: Optional[str] = None
+    rule: Optional[str] = None
+
+
+@dataclass
+class MotionStopped:
+    detector: MotionDetector
+    video_source: Optional[str] = None
+    rule: Optional[str] = None
+
+
+@dataclass
+class DigitalInputChanged:
+    input_token: Optional[str]
+    active: bool
+
+
+@dataclass
+class UnknownEvent:
+    """A topic without a typed form, with the message items as the camera sent them."""
+
+    source: dict[str, str]
+    data: dict[str, str]
+
+
+CameraEventKind = Union[MotionStarted, MotionStopped, DigitalInputChanged, UnknownEvent]
+
+# Topic path -> detector and the Data item holding its state.
+MOTION_TOPICS = {
+    "RuleEngine/CellMotionDetector/Motion": (MotionDetector.CELL_MOTION, "IsMotion"),
+    "VideoSource/MotionAlarm": (MotionDetector.MOTION_ALARM, "State"),
+}
+DIGITAL_INPUT_TOPIC = "Device/Trigger/DigitalInput"
+# Source items naming the video source, by how common they are.
+VIDEO_SOURCE_ITEMS = ("VideoSourceConfigurationToken", "VideoSourceToken", "Source")
+
+
+def topic_path(topic: str) -> str:
+    """`tns1:RuleEngine/CellMotionDetector/Motion` as `RuleEngine/CellMotionDetector/Motion`."""
+    # Cameras bind the ONVIF topic namespace to a prefix of their choice, some on every segment,
+    # and some end concrete topics in `//.`.
+    path = topic.strip()
+    if path.endswith("//."):
+        path = path[: -len("//.")]
+    return "/".join(segment.rsplit(":", 1)[-1] for segment in path.strip("/").split("/"))
+
+
+def _state(value) -> Optional[bool]:
+    value = str(value).strip().lower()
+    if value in ("true", "1"):
+        return True
+    if value in ("false", "0"):
+        return False
+    return None
+
+
+def event_kind(topic: str, source: dict[str, str], data: dict[str, str]) -> CameraEventKind:
+    path = topic_path(topic)
+    if path in MOTION_TOPICS:
+        detector, state_item = MOTION_TOPICS[path]
+        active = _state(data.get(state_item))
+        if active is not None:
+            video_source = next((source[name] for name in VIDEO_SOURCE_ITEMS if name in source), None)
+            kind = MotionStarted if active else MotionStopped
+            return kind(detector, video_source=video_source, rule=source.get("Rule"))
+    elif path == DIGITAL_INPUT_TOPIC:
+        active = _state(data.get("LogicalState"))
+        if active is not None:
+            return DigitalInputChanged(input_token=source.get("InputToken"), active=active)
+    else:
+        return UnknownEvent(source, data)
+
+    logger.debug(f"Event on {topic} without a readable state: {data}")
+    return UnknownEvent(source, data)
+
+
+@dataclass
+class CameraEvent:
+    topic: str
+    utc_time: datetime
+    source: dict[str, str] = field(default_factory=dict)
+    data: dict[str, str] = field(default_factory=dict)
+
+    @property
+    def kind(self) -> CameraEventKind:
+        return event_kind(self.topic, self.source, self.data)
+
+    def to_json(self) -> str:
+        """The typed form, e.g. `{"type": "MotionStarted", "detector": "cell_motion", ...}`."""
+        kind = self.kind
+        return json.dumps(
+            {"type": type(kind).__name__, "topic": self.topic, "utc_time": self.utc_time.isoformat(), **asdict(kind)}
+        )
+
+
+def supports_events(camera: ONVIFCamera) -> bool:
+    return EVENTS_NAMESPACE in camera.xaddrs
+
+
+def _parse_time(value) -> datetime:
+    if isinstance(value, datetime):
+        return value if value.tzinfo else value.replace(tzinfo=timezone.utc)
+    if value:
+        try:
+            return datetime.fromisoformat(str(value).replace("Z", "+00:00"))
+        except ValueError:
+            pass
+    return datetime.now(timezone.utc)
+
+
+def _simple_items(message, part: str) -> dict[str, str]:
+    # zeep leaves the tt:Message payload as a raw element when it cannot map it onto the schema.
+    if isinstance(message, etree._Element):
+        items = {}
+        for node in message.iter():
+            if etree.QName(node).localname != part:
+                continue
+            for item in node:
+                if etree.QName(item).localname == "SimpleItem":
+                    items[item.get("Name")] = item.get("Value")
+        return items
+
+    container = getattr(message, part, None)
+    return {item.Name: item.Value for item in (getattr(container, "SimpleItem", None) or [])}
+
+
+def parse_notification(notification) -> Optional[CameraEvent]:
+    try:
+        topic = notification.Topic._value_1
+        message = notification.Message._value_1
+    except AttributeError:
+        logger.debug(f"Ignoring notification without topic or message: {notification}")
+        return None
+
+    utc_time = message.get("UtcTime") if isinstance(message, etree._Element) else getattr(message, "UtcTime", None)
+    return CameraEvent(
+        topic=str(topic).strip(),
+        utc_time=_parse_time(utc_time),
+        source=_simple_items(message, "Source"),
+        data=_simple_items(message, "Data"),
+    )
+
+
+class PullPointSubscription:
+    """
+    An ONVIF PullPoint subscription that renews itself before the camera terminates it.
+    """
+
+    def __init__(self, camera: ONVIFCamera, lifetime: timedelta = timedelta(seconds=60)):
+        self.camera = camera
+        self.lifetime = lifetime
+        self.pullpoint = None
+        self.manager = None
+        self.renew_at = None
+
+    @property
+    def _termination(self) -> str:
+        return f"PT{int(self.lifetime.total_seconds())}S"
+
+    def create(self):
+        with onvif_errors("create event service"):
+            events_service = create_service(self.camera, "events")
+        request = {"InitialTerminationTime": self._termination}
+        subscription = call(events_service, "CreatePullPointSubscription", request)
+        address = subscription.SubscriptionReference.Address._value_1
+
+        # onvif-zeep looks service addresses up by namespace + port type.
+        self.camera.xaddrs[f"{EVENTS_NAMESPACE}/PullPointSubscription"] = address
+        self.camera.xaddrs[f"{EVENTS_NAMESPACE}/SubscriptionManager"] = address
+        with onvif_errors("create subscription services"):
+            self.pullpoint = create_service(self.camera, "pullpoint", port_type="PullPointSubscription")
+            self.manager = create_service(self.camera, "subscription", port_type="SubscriptionManager")
+        self._schedule_renewal()
+
+    def _schedule_renewal(self):
+        # Renew halfway through the lifetime to leave room for slow responses.
+        self.renew_at = datetime.now(timezone.utc) + self.lifetime / 2
+
+    def renew(self):
+        try:
+            call(self.manager, "Renew", {"TerminationTime": self._termination})
+            self._schedule_renewal()
+        except OnvifError as e:
+            logger.warning(f"Renewing the event subscription failed ({e}), creating a new one.")
+            self.discard()
+            self.create()
+
+    def unsubscribe(self):
+        """End the subscription, so it doesn't count against the camera's subscription limit until it expires."""
+        if self.manager is None:
+            return
+        call(self.manager, "Unsubscribe")
+        self.pullpoint = None
+        self.manager = None
+
+    def discard(self):
+        """Unsubscribe as far as the camera still answers, before the subscription is replaced by a new one."""
+        try:
+            self.unsubscribe()
+        except OnvifError as e:
+            logger.warning(f"Unsubscribing from the old event subscription failed: {e}")
+        self.pullpoint = None
+        self.manager = None
+
+    def pull(self, timeout: timedelta = timedelta(seconds=10), limit: int = 100) -> list[CameraEvent]:
+        if self.pullpoint is None:
+            self.create()
+        elif datetime.now(timezone.utc) >= self.renew_at:
+            self.renew()
+
+        # The camera holds PullMessages open until the timeout, which must not keep other requests waiting.
+        try:
+            with self.camera.transport.long_poll():
+                response = call(self.pullpoint, "PullMessages", {"Timeout": timeout, "MessageLimit": limit})
+        except OnvifError:
+            # The next pull subscribes again.
+            self.discard()
+            raise
+
+        # An empty response just means nothing happened during the timeout.
+        notifications = getattr(response, "NotificationMessage", None) or []
+        events = [parse_notification(notification) for notification in notifications]
+        return [event for event in events if event is not None]
+
+
+async def pull_events(camera: ONVIFCamera, topic, entity_path: str):
+    """Publish camera events until cancelled, then unsubscribe."""
+    subscription = PullPointSubscription(camera)
+    try:
+        while True:
+            try:
+                events = await asyncio.to_thread(subscription.pull)
+            except OnvifError as e:
+                logger.warning(f"Pulling events failed: {e}")
+                await asyncio.sleep(5)
+                continue
+
+            for event in events:
+                header = Header(entity_path=entity_path)
+                header.timestamp.FromDatetime(event.utc_time)
+                await asyncio.to_thread(topic.publish, PlainText(header=header, body=event.to_json()))
+    finally:
+        try:
+            await asyncio.to_thread(subscription.unsubscribe)
+        except OnvifError as e:
+            logger.warning(f"Unsubscribing from events failed: {e}")
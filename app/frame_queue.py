@@ -0,0 +1,180 @@
+import collections
+import contextvars
+import logging
+import threading
+from dataclasses import dataclass
+from typing import Any, Callable, Optional
+
+logger = logging.getLogger(__name__)
+
+OVERFLOW_POLICIES = ("drop_oldest", "drop_newest", "block")
+
+
+@dataclass
+class QueuedFrame:
+    message: Any
+    # Frames following a dropped frame are undecodable up to the next keyframe (audio frames always are one).
+    is_keyframe: bool
+    # Payload bytes, for the published byte count.
+    size: int = 0
+
+
+class FrameQueue:
+    """
+    Bounded hand-over between the thread reading the RTSP stream and the thread publishing to make87,
+    so a slow consumer can't make frames pile up in memory.
+
+    When full, "drop_oldest" discards queued frames up to the latest queued keyframe, "drop_newest" discards
+    the incoming frame and "block" stalls the reader. After dropping, frames are skipped until the next
+    keyframe, so consumers never receive a GOP with a hole in it.
+    """
+
+    def __init__(self, capacity: int, policy: str = "drop_oldest"):
+        self.capacity = capacity
+        self.policy = policy
+        self.dropped = 0
+        self._frames: collections.deque[QueuedFrame] = collections.deque()
+        self._awaiting_keyframe = False
+        self._closed = False
+        self._condition = threading.Condition()
+
+    def put(self, frame: QueuedFrame):
+        with self._condition:
+            if self._awaiting_keyframe:
+                if not frame.is_keyframe:
+                    self.dropped += 1
+                    return
+                self._awaiting_keyframe = False
+
+            if len(self._frames) >= self.capacity:
+                if self.policy == "block":
+                    self._condition.wait_for(lambda: len(self._frames) < self.capacity or self._closed)
+                elif self.policy == "drop_newest":
+                    self._drop_incoming()
+                    return
+                else:
+                    self._drop_oldest()
+                    if self._awaiting_keyframe and not frame.is_keyframe:
+                        self._drop_incoming()
+                        return
+                    self._awaiting_keyframe = False
+
+            if self._closed:
+                return
+            self._frames.append(frame)
+            self._condition.notify_all()
+
+    def _drop_incoming(self):
+        self.dropped += 1
+        self._awaiting_keyframe = True
+
+    def _drop_oldest(self):
+        latest_keyframe = max((i for i, frame in enumerate(self._frames) if frame.is_keyframe), default=0)
+        if latest_keyframe > 0:
+            for _ in range(latest_keyframe):
+                self._frames.popleft()
+            self.dropped += latest_keyframe
+        else:
+            # The queue holds a single GOP (or none), so nothing can go without breaking what follows it.
+            self.dropped += len(self._frames)
+            self._frames.clear()
+            self._awaiting_keyframe = True
+
+    def get(self) -> Optional[QueuedFrame]:
+        """The next frame, waiting for one; `None` once the queue is closed and empty."""
+        with self._condition:
+            self._condition.wait_for(lambda: self._frames or self._closed)
+            if not self._frames:
+                return None
+            frame = self._frames.popleft()
+            self._condition.notify_all()
+            return frame
+
+    def close(self, discard: bool = False):
+        """Wake up both sides. Queued frames can still be taken, unless they are discarded."""
+        with self._condition:
+            self._closed = True
+            if discard:
+                self._frames.clear()
+            self._condition.notify_all()
+
+
+class FrameRateLimiter:
+    """
+    Thins a stream out to about `fps` frames per second of media time, for links that can't carry every frame.
+    Keyframes always pass. Frames no other frame references are dropped on their own; once a reference frame
+    has to go, the rest of its GOP goes with it, since it could no longer be decoded.
+    """
+
+    # Seconds of frames the budget saves up while a GOP tail is dropped, so the next GOP keeps its first frames.
+    BURST = 10.0
+
+    def __init__(self, fps: float):
+        self.fps = fps
+        self.dropped = 0
+        self._budget = 1.0
+        self._last_timestamp: Optional[float] = None
+        self._gop_broken = False
+
+    def allow(self, timestamp: float, is_keyframe: bool, disposable: bool) -> bool:
+        """Whether to publish a frame presented at `timestamp` seconds."""
+        if self._last_timestamp is not None:
+            elapsed = max(0.0, timestamp - self._last_timestamp)
+            self._budget = min(self.fps * self.BURST, self._budget + elapsed * self.fps)
+        self._last_timestamp = timestamp
+
+        if is_keyframe:
+            self._gop_broken = False
+        elif self._gop_broken or self._budget < 1.0 - 1e-6:  # Tolerates time base rounding.
+            if not disposable:
+                self._gop_broken = True
+            self.dropped += 1
+            return False
+        self._budget -= 1.0
+        return True
+
+
+class FramePublisher:
+    """
+    Publishes queued frames on `topic` from a worker thread, so publishing never stalls the RTSP reader.
+    `on_published` is called with the size of every frame published.
+    """
+
+    def __init__(
+        self,
+        topic,
+        queue: FrameQueue,
+        on_published: Optional[Callable[[int], None]] = None,
+        stop: Optional[threading.Event] = None,
+    ):
+        self.topic = topic
+        self.queue = queue
+        self.on_published = on_published
+        self.stop = stop
+        self.published = 0
+        # The worker inherits the camera's log context.
+        self._thread = threading.Thread(target=contextvars.copy_context().run, args=(self._run,), daemon=True)
+
+    def put(self, message, is_keyframe: bool, size: int = 0):
+        self.queue.put(QueuedFrame(message, is_keyframe, size))
+
+    def __enter__(self):
+        self._thread.start()
+        return self
+
+    def __exit__(self, exc_type, *exc_info):
+        # A stream that ended by itself is published to the end; a stopped or broken one is not.
+        stopped = self.stop is not None and self.stop.is_set()
+        self.queue.close(discard=stopped or exc_type is not None)
+        self._thread.join()
+
+    def _run(self):
+        while (frame := self.queue.get()) is not None:
+            try:
+                self.topic.publish(frame.message)
+            except Exception as e:
+                logger.error(f"Publishing frame failed: {e}")
+                continue
+            self.published += 1
+            if self.on_published is not None:
+                self.on_published(frame.size)
@@ -0,0 +1,131 @@
+import asyncio
+import json
+import threading
+import time
+from dataclasses import asdict, dataclass, field, replace
+from datetime import datetime
+from typing import Optional
+
+from make87_messages.core.header_pb2 import Header
+from make87_messages.text.text_plain_pb2 import PlainText
+
+from app.connection import ConnectionState
+
+
+@dataclass
+class CameraHealth:
+    camera_id: str
+    connected: bool
+    last_frame_age_ms: int
+    consecutive_errors: int
+    # Streams (main or sub) the watchdog restarted because no frames arrived.
+    stream_restarts: int
+
+
+@dataclass
+class StreamCounters:
+    frames_published: int = 0
+    bytes_published: int = 0
+    # Frame rate the camera delivered over the last metrics interval; 0 while not streaming.
+    fps: float = 0.0
+
+
+@dataclass
+class CameraMetrics:
+    camera_id: str
+    connection_state: Optional[ConnectionState]
+    reconnects: int
+    stream_restarts: int
+    # "main" and, once it ran, "sub".
+    streams: dict[str, StreamCounters] = field(default_factory=dict)
+    ptz_commands: int = 0
+
+
+class CameraStatus:
+    """Thread-safe connection bookkeeping for one camera, updated by its stream and reconnect logic."""
+
+    def __init__(self, camera_id: str, entity_path: str):
+        self.camera_id = camera_id
+        self.entity_path = entity_path
+        self._lock = threading.Lock()
+        self._connected = False
+        self._consecutive_errors = 0
+        self._stream_restarts = 0
+        self._reconnects = 0
+        self._connection_state = None
+        self._streams: dict[str, StreamCounters] = {}
+        # Until the first frame arrives, the frame age counts from startup so a stream that never starts shows up.
+        self._last_frame_at = time.monotonic()
+
+    def frame_published(self, size: int = 0, stream: str = "main"):
+        with self._lock:
+            if stream == "main":
+                self._last_frame_at = time.monotonic()
+            counters = self._streams.setdefault(stream, StreamCounters())
+            counters.frames_published += 1
+            counters.bytes_published += size
+
+    def stream_measured(self, stream: str, fps: float):
+        with self._lock:
+            self._streams.setdefault(stream, StreamCounters()).fps = fps
+
+    def connection_changed(self, state: ConnectionState):
+        with self._lock:
+            self._connection_state = state
+            if state == ConnectionState.RECONNECTING:
+                self._reconnects += 1
+            if state != ConnectionState.STREAMING:
+                for counters in self._streams.values():
+                    counters.fps = 0.0
+
+    def streaming(self):
+        with self._lock:
+            self._connected = True
+            self._consecutive_errors = 0
+
+    def failed(self):
+        with self._lock:
+            self._connected = False
+            self._consecutive_errors += 1
+
+    def disconnected(self):
+        with self._lock:
+            self._connected = False
+
+    def stream_stalled(self):
+        with self._lock:
+            self._stream_restarts += 1
+
+    def health(self) -> CameraHealth:
+        with self._lock:
+            return CameraHealth(
+                camera_id=self.camera_id,
+                connected=self._connected,
+                last_frame_age_ms=int((time.monotonic() - self._last_frame_at) * 1000),
+                consecutive_errors=self._consecutive_errors,
+                stream_restarts=self._stream_restarts,
+            )
+
+    def metrics(self) -> CameraMetrics:
+        with self._lock:
+            return CameraMetrics(
+                camera_id=self.camera_id,
+                connection_state=self._connection_state,
+                reconnects=self._reconnects,
+                stream_restarts=self._stream_restarts,
+                streams={stream: replace(counters) for stream, counters in self._streams.items()},
+            )
+
+
+def publish_health(topic, status: CameraStatus):
+    header = Header(entity_path=status.entity_path)
+    header.timestamp.FromDatetime(datetime.now())
+    topic.publish(PlainText(header=header, body=json.dumps(asdict(status.health()))))
+
+
+async def report_health(topic, statuses: list[CameraStatus], interval: float):
+    """Publish the health of every camera each `interval` seconds until cancelled."""
+    while True:
+        await asyncio.sleep(interval)
+        for status in statuses:
+            await asyncio.to_thread(publish_health, topic, status)
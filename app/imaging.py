@@ -0,0 +1,210 @@
+import json
+import logging
+import threading
+from dataclasses import dataclass, field
+from typing import Optional
+
+from make87_messages.text.text_plain_pb2 import PlainText
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, SoapError, onvif_errors
+from app.logs import camera_context
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+IMAGING_NAMESPACE = "http://www.onvif.org/ver20/imaging/wsdl"
+# Command fields and the ImagingSettings20 elements they set.
+IMAGING_PARAMETERS = {
+    "brightness": "Brightness",
+    "contrast": "Contrast",
+    "saturation": "ColorSaturation",
+    "sharpness": "Sharpness",
+}
+FOCUS_MODES = {"auto": "AUTO", "manual": "MANUAL"}
+
+
+@dataclass
+class FloatRange:
+    min: float
+    max: float
+
+
+@dataclass
+class ImagingOptions:
+    # Only parameters the camera reports a range for can be set.
+    ranges: dict[str, FloatRange] = field(default_factory=dict)
+    focus_modes: set[str] = field(default_factory=set)
+    focus_position: Optional[FloatRange] = None
+
+
+@dataclass
+class ImagingSettings:
+    brightness: Optional[float] = None
+    contrast: Optional[float] = None
+    saturation: Optional[float] = None
+    sharpness: Optional[float] = None
+    # AUTO or MANUAL
+    focus_mode: Optional[str] = None
+
+
+def supports_imaging(camera: ONVIFCamera) -> bool:
+    return IMAGING_NAMESPACE in camera.xaddrs
+
+
+def _float_range(value) -> Optional[FloatRange]:
+    if value is None or value.Min is None or value.Max is None:
+        return None
+    return FloatRange(float(value.Min), float(value.Max))
+
+
+def get_imaging_options(imaging_service, video_source_token: str) -> ImagingOptions:
+    options = call(imaging_service, "GetOptions", {"VideoSourceToken": video_source_token})
+
+    imaging_options = ImagingOptions()
+    for name, element in IMAGING_PARAMETERS.items():
+        value_range = _float_range(getattr(options, element, None))
+        if value_range is not None:
+            imaging_options.ranges[name] = value_range
+    if options.Focus is not None:
+        imaging_options.focus_modes = set(options.Focus.AutoFocusModes or [])
+
+    try:
+        move_options = call(imaging_service, "GetMoveOptions", {"VideoSourceToken": video_source_token})
+        if move_options.Absolute is not None:
+            imaging_options.focus_position = _float_range(move_options.Absolute.Position)
+    except SoapError as e:
+        logger.debug(f"Camera does not support focus moves: {e}")
+
+    return imaging_options
+
+
+def get_imaging_settings(imaging_service, video_source_token: str) -> ImagingSettings:
+    settings = call(imaging_service, "GetImagingSettings", {"VideoSourceToken": video_source_token})
+
+    imaging_settings = ImagingSettings(
+        focus_mode=settings.Focus.AutoFocusMode if settings.Focus is not None else None,
+    )
+    for name, element in IMAGING_PARAMETERS.items():
+        value = getattr(settings, element, None)
+        setattr(imaging_settings, name, float(value) if value is not None else None)
+    return imaging_settings
+
+
+def set_imaging_settings(
+    imaging_service, video_source_token: str, values: dict[str, float], focus_mode: Optional[str] = None
+):
+    """
+    Update the given parameters, keeping all others. The current settings are sent back along with the changes,
+    because some cameras reset elements that are missing from SetImagingSettings.
+    """
+    settings = call(imaging_service, "GetImagingSettings", {"VideoSourceToken": video_source_token})
+    for name, value in values.items():
+        setattr(settings, IMAGING_PARAMETERS[name], value)
+    if focus_mode is not None:
+        if settings.Focus is None:
+            settings.Focus = {"AutoFocusMode": focus_mode}
+        else:
+            settings.Focus.AutoFocusMode = focus_mode
+    call(
+        imaging_service,
+        "SetImagingSettings",
+        {"VideoSourceToken": video_source_token, "ImagingSettings": settings, "ForcePersistence": True},
+    )
+
+
+def move_focus(imaging_service, video_source_token: str, position: float):
+    request = {"VideoSourceToken": video_source_token, "Focus": {"Absolute": {"Position": position}}}
+    call(imaging_service, "Move", request)
+
+
+def clamp(name: str, value: float, value_range: FloatRange) -> float:
+    if value_range.min <= value <= value_range.max:
+        return value
+    clamped = max(value_range.min, min(value_range.max, value))
+    logger.warning(f"Imaging {name} {value} is outside [{value_range.min}, {value_range.max}], clamping to {clamped}.")
+    return clamped
+
+
+class ImagingController:
+    """
+    Applies imaging commands received on a topic, e.g. `{"brightness": 60, "focus_mode": "manual", "focus": 0.4}`.
+    Values are clamped to the ranges the camera reports in GetOptions; unsupported parameters are ignored.
+    """
+
+    def __init__(self, camera_id: str):
+        self.camera_id = camera_id
+        self.imaging_service = None
+        self.video_source_token = None
+        self.options = ImagingOptions()
+        self._lock = threading.Lock()
+
+    def attach(self, camera: ONVIFCamera, video_source_token: str):
+        with onvif_errors("create imaging service"):
+            imaging_service = create_service(camera, "imaging")
+        options = get_imaging_options(imaging_service, video_source_token)
+        logger.info(f"Imaging settings: {get_imaging_settings(imaging_service, video_source_token)}")
+
+        with self._lock:
+            self.imaging_service = imaging_service
+            self.video_source_token = video_source_token
+            self.options = options
+
+    def detach(self):
+        with self._lock:
+            self.imaging_service = None
+            self.video_source_token = None
+
+    def handle_command(self, message: PlainText):
+        # Commands arrive on make87 subscriber threads, outside of the camera's task.
+        with camera_context(self.camera_id):
+            self._handle_command(message)
+
+    def _handle_command(self, message: PlainText):
+        try:
+            command = json.loads(message.body)
+            # Commands without a "camera" field address every camera.
+            if command.get("camera", self.camera_id) != self.camera_id:
+                return
+            values = {name: float(command[name]) for name in IMAGING_PARAMETERS if command.get(name) is not None}
+            focus_mode = command.get("focus_mode")
+            if focus_mode is not None and str(focus_mode).lower() not in FOCUS_MODES:
+                raise ValueError(f"focus_mode must be one of {', '.join(FOCUS_MODES)}")
+            focus_position = float(command["focus"]) if command.get("focus") is not None else None
+        except (ValueError, TypeError, AttributeError) as e:
+            logger.warning(f"Ignoring malformed imaging command {message.body!r}: {e}")
+            return
+
+        with self._lock:
+            if self.imaging_service is None:
+                logger.warning("No camera with an imaging service connected, dropping imaging command.")
+                return
+
+            for name in list(values):
+                if name not in self.options.ranges:
+                    logger.warning(f"Camera does not support setting {name}, ignoring it.")
+                    del values[name]
+                else:
+                    values[name] = clamp(name, values[name], self.options.ranges[name])
+
+            if focus_mode is not None:
+                focus_mode = FOCUS_MODES[str(focus_mode).lower()]
+                if focus_mode not in self.options.focus_modes:
+                    logger.warning(f"Camera does not support focus mode {focus_mode}, ignoring it.")
+                    focus_mode = None
+
+            if focus_position is not None:
+                if self.options.focus_position is None:
+                    logger.warning("Camera does not support absolute focus moves, ignoring focus.")
+                    focus_position = None
+                else:
+                    focus_position = clamp("focus", focus_position, self.options.focus_position)
+
+            try:
+                if values or focus_mode is not None:
+                    set_imaging_settings(self.imaging_service, self.video_source_token, values, focus_mode)
+                if focus_position is not None:
+                    move_focus(self.imaging_service, self.video_source_token, focus_position)
+            except OnvifError as e:
+                logger.error(f"Imaging command failed: {e}")
@@ -0,0 +1,129 @@
+import json
+import logging
+import threading
+from dataclasses import dataclass
+from typing import Optional
+
+from make87_messages.text.text_plain_pb2 import PlainText
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, SoapError, onvif_errors
+from app.logs import camera_context
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+RELAY_STATES = {"on": True, "active": True, "off": False, "inactive": False}
+
+
+@dataclass
+class RelayOutput:
+    token: str
+    # Monostable relays return to their idle state by themselves after `delay_time`, bistable ones stay.
+    mode: str
+    delay_time: Optional[str] = None
+    idle_state: Optional[str] = None
+
+    @property
+    def monostable(self) -> bool:
+        return self.mode == "Monostable"
+
+
+def get_relay_outputs(device_service) -> list[RelayOutput]:
+    relays = call(device_service, "GetRelayOutputs")
+
+    outputs = []
+    for relay in relays or []:
+        properties = relay.Properties
+        outputs.append(
+            RelayOutput(
+                token=relay.token,
+                mode=properties.Mode,
+                delay_time=str(properties.DelayTime) if properties.DelayTime is not None else None,
+                idle_state=properties.IdleState,
+            )
+        )
+    return outputs
+
+
+def set_relay_output_state(device_service, relay_token: str, active: bool):
+    call(
+        device_service,
+        "SetRelayOutputState",
+        {"RelayOutputToken": relay_token, "LogicalState": "active" if active else "inactive"},
+    )
+
+
+class RelayController:
+    """
+    Switches relay outputs on commands like `{"relay": "RelayOutputToken_1", "state": "on"}`.
+    Only relays the camera reports in GetRelayOutputs are accepted.
+    """
+
+    def __init__(self, camera_id: str):
+        self.camera_id = camera_id
+        self.device_service = None
+        self.relays: dict[str, RelayOutput] = {}
+        self._lock = threading.Lock()
+
+    def attach(self, camera: ONVIFCamera):
+        with onvif_errors("create device service"):
+            device_service = create_service(camera, "devicemgmt")
+        try:
+            relays = get_relay_outputs(device_service)
+        except SoapError as e:
+            logger.info(f"Camera does not report relay outputs: {e}")
+            relays = []
+        if relays:
+            logger.info(f"Relay outputs: {', '.join(f'{relay.token} ({relay.mode})' for relay in relays)}")
+
+        with self._lock:
+            self.device_service = device_service
+            self.relays = {relay.token: relay for relay in relays}
+
+    def detach(self):
+        with self._lock:
+            self.device_service = None
+            self.relays = {}
+
+    def handle_command(self, message: PlainText):
+        # Commands arrive on make87 subscriber threads, outside of the camera's task.
+        with camera_context(self.camera_id):
+            self._handle_command(message)
+
+    def _handle_command(self, message: PlainText):
+        try:
+            command = json.loads(message.body)
+            # Commands without a "camera" field address every camera.
+            if command.get("camera", self.camera_id) != self.camera_id:
+                return
+            relay_token = command["relay"]
+            if not isinstance(relay_token, str):
+                raise TypeError("relay must be a relay output token")
+            state = command["state"]
+            active = state if isinstance(state, bool) else RELAY_STATES[str(state).lower()]
+        except (ValueError, TypeError, AttributeError, KeyError) as e:
+            logger.warning(f"Ignoring malformed relay command {message.body!r}: {e}")
+            return
+
+        with self._lock:
+            if self.device_service is None:
+                logger.warning("No camera connected, dropping relay command.")
+                return
+            relay = self.relays.get(relay_token)
+            if relay is None:
+                available = ", ".join(self.relays) or "none"
+                logger.warning(f"Camera has no relay output {relay_token!r} (available: {available}), ignoring it.")
+                return
+
+            try:
+                set_relay_output_state(self.device_service, relay.token, active)
+            except OnvifError as e:
+                logger.error(f"Switching relay {relay.token} failed: {e}")
+                return
+
+        if active and relay.monostable:
+            logger.info(f"Relay {relay.token} activated, it resets itself after {relay.delay_time}.")
+        else:
+            logger.info(f"Relay {relay.token} {'activated' if active else 'deactivated'}.")
@@ -0,0 +1,81 @@
+import contextvars
+import copy
+import logging
+from contextlib import contextmanager
+from typing import Optional
+
+from lxml import etree
+from zeep import Plugin
+
+from app.secret import REDACTED, redact
+
+logger = logging.getLogger(__name__)
+
+LOG_FORMAT = "%(asctime)s %(levelname)s %(name)s [%(camera)s] %(message)s"
+# Elements of a WS-Security UsernameToken that must never end up in a log.
+REDACTED_ELEMENTS = {"Password", "Nonce"}
+
+# Asyncio tasks and `asyncio.to_thread` copy the context, so everything a camera task does is tagged with it.
+_camera_context = contextvars.ContextVar("camera", default="-")
+
+
+class CameraContextFilter(logging.Filter):
+    def filter(self, record: logging.LogRecord) -> bool:
+        record.camera = _camera_context.get()
+        return True
+
+
+class RedactingFormatter(logging.Formatter):
+    """Scrubs secrets from the final log line, including tracebacks and messages of third-party libraries."""
+
+    def format(self, record: logging.LogRecord) -> str:
+        return redact(super().format(record))
+
+
+def setup_logging(level: str):
+    handler = logging.StreamHandler()
+    handler.setFormatter(RedactingFormatter(LOG_FORMAT))
+    handler.addFilter(CameraContextFilter())
+    logging.basicConfig(level=level.upper(), handlers=[handler], force=True)
+
+
+def set_camera_context(camera_id: str, xaddr: Optional[str] = None):
+    """Tag all following log lines of the current task/thread with the camera (and its service address)."""
+    _camera_context.set(f"{camera_id} {xaddr}" if xaddr else camera_id)
+
+
+@contextmanager
+def camera_context(camera_id: str, xaddr: Optional[str] = None):
+    """Like `set_camera_context`, for code running in threads that don't inherit a camera task's context."""
+    token = _camera_context.set(f"{camera_id} {xaddr}" if xaddr else camera_id)
+    try:
+        yield
+    finally:
+        _camera_context.reset(token)
+
+
+def redact_envelope(envelope: etree._Element) -> etree._Element:
+    redacted = copy.deepcopy(envelope)
+    for element in redacted.iter():
+        if isinstance(element.tag, str) and etree.QName(element).localname in REDACTED_ELEMENTS:
+            element.text = REDACTED
+    return redacted
+
+
+class SoapLoggingPlugin(Plugin):
+    """Logs SOAP envelopes at debug level, with the UsernameToken password digest and nonce redacted."""
+
+    def egress(self, envelope, http_headers, operation, binding_options):
+        self._log("Request", envelope, operation)
+        return envelope, http_headers
+
+    def ingress(self, envelope, http_headers, operation):
+        self._log("Response", envelope, operation)
+        return envelope, http_headers
+
+    @staticmethod
+    def _log(direction: str, envelope, operation):
+        if not logger.isEnabledFor(logging.DEBUG):
+            return
+        body = etree.tostring(redact_envelope(envelope), pretty_print=True, encoding="unicode")
+        logger.debug(f"{direction} {operation.name}:\n{body}")
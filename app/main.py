@@ -1,183 +1,639 @@
+import asyncio
+import json
 import logging
-from datetime import datetime, timedelta
+import os
+import signal
+import sys
+from concurrent.futures import ThreadPoolExecutor
+from dataclasses import asdict, dataclass, replace
+from datetime import datetime
+from typing import Callable, Optional
 
-import av
 import make87
 from make87_messages.core.header_pb2 import Header
+from make87_messages.image.compressed.image_jpeg_pb2 import ImageJPEG
+from make87_messages.text.text_plain_pb2 import PlainText
 from make87_messages.video.any_pb2 import FrameAny
-from make87_messages.video.frame_av1_pb2 import FrameAV1
-from make87_messages.video.frame_h264_pb2 import FrameH264
-from make87_messages.video.frame_h265_pb2 import FrameH265
 from onvif import ONVIFCamera
-from urllib.parse import urlparse, urlunparse
 
-logging.basicConfig(level=logging.INFO)
+from app.auth import connect
+from app.bootstrap import bootstrap_cameras, publish_bootstrap_config
+from app.config import CameraConfig, DriverConfig, load_config, load_log_level, parse_url
+from app.connection import ConnectionState, ConnectionStatePublisher
+from app.device import MaintenanceController, get_device_information, get_network_interfaces
+from app.discovery import discover_devices
+from app.error import CameraRestarting, ConfigError, OnvifError, SoapError, TopicResolutionError
+from app.events import pull_events, supports_events
+from app.health import CameraMetrics, CameraStatus, publish_health, report_health
+from app.imaging import ImagingController, supports_imaging
+from app.io import RelayController
+from app.logs import set_camera_context, setup_logging
+from app.media import (
+    MediaProfile,
+    OsdController,
+    create_media_service,
+    get_profiles,
+    get_snapshot_uri,
+    get_stream_uri,
+    select_lowest_resolution_video,
+    select_preferred_profile,
+)
+from app.prometheus import MetricsServer, render_metrics
+from app.ptz import PtzController, poll_ptz_status, supports_ptz
+from app.retry import BackoffPolicy, with_backoff
+from app.rtsp import RtspSettings, StreamMetrics, inject_rtsp_auth, run_stream
+from app.services import discover_services
+from app.snapshot import SnapshotResponder, poll_snapshots
+from app.stream_info import VideoStreamInfo
+from app.time_sync import sync_time
+
 logger = logging.getLogger(__name__)
 
 
-def parse_url(url):
-    parsed = urlparse(url)
-    protocol = parsed.scheme
-    ip = parsed.hostname
-    port = parsed.port  # Will be None if not specified in the URL
-    url_suffix = parsed.path  # The part after the IP and port
+def get_publisher(name: str, message_type):
+    try:
+        return make87.get_publisher(name=name, message_type=message_type)
+    except Exception as e:
+        raise TopicResolutionError(f"Could not resolve publisher topic {name}: {e}") from e
 
-    return protocol, ip, port, url_suffix
 
+def get_subscriber(name: str, message_type):
+    try:
+        return make87.get_subscriber(name=name, message_type=message_type)
+    except Exception as e:
+        raise TopicResolutionError(f"Could not resolve subscriber topic {name}: {e}") from e
 
-def inject_rtsp_auth(uri: str, username: str, password: str) -> str:
-    parsed = urlparse(uri)
 
-    netloc_with_auth = f"{username}:{password}@{parsed.hostname}"
-    if parsed.port:
-        netloc_with_auth += f":{parsed.port}"
+def publish_discovered_devices(topic, timeout: float):
+    try:
+        cameras = discover_devices(timeout=timeout)
+    except OSError as e:
+        logger.warning(f"WS-Discovery failed: {e}")
+        return
+    logger.info(f"Discovered {len(cameras)} ONVIF device(s) on the local network.")
 
-    return urlunparse(
-        (
-            parsed.scheme,
-            netloc_with_auth,
-            parsed.path,
-            parsed.params,
-            parsed.query,
-            parsed.fragment,
-        )
-    )
+    for camera in cameras:
+        logger.info(f"Found {camera.scope_info.name or camera.endpoint} at {', '.join(camera.xaddrs)}")
+        header = Header(entity_path="/discovery")
+        header.timestamp.FromDatetime(datetime.now())
+        topic.publish(PlainText(header=header, body=json.dumps(asdict(camera))))
 
 
-# Generic function for encoding frames
-def encode_frame(codec, header, packet: av.Packet, width: int, height: int) -> FrameAny:
-    codec_classes = {
-        "h264": ("h264", FrameH264),
-        "hevc": ("h265", FrameH265),
-        "av1": ("av1", FrameAV1),
-    }
+def publish_device_information(topic, camera: ONVIFCamera, entity_path: str):
+    try:
+        info = get_device_information(camera)
+    except SoapError as e:
+        logger.warning(f"Camera rejected GetDeviceInformation: {e}")
+        return
 
-    if codec not in codec_classes:
-        raise ValueError(f"Unsupported codec: {codec}")
-
-    codec_field, codec_class = codec_classes[codec]
-    sub_message = codec_class(
-        header=header,
-        data=bytes(packet),
-        width=width,
-        height=height,
-        is_keyframe=packet.is_keyframe,
-        pts=packet.pts,
-        dts=packet.dts,
-        duration=packet.duration,
-        time_base=codec_class.Fraction(
-            num=packet.time_base.numerator,
-            den=packet.time_base.denominator,
-        ),
-    )
+    logger.info(f"Connected to {info.manufacturer} {info.model} (serial {info.serial_number})")
 
-    return FrameAny(header=header, **{codec_field: sub_message})
+    header = Header(entity_path=entity_path)
+    header.timestamp.FromDatetime(datetime.now())
+    topic.publish(PlainText(header=header, body=json.dumps(asdict(info))))
 
 
-def check_annex_b_format(packet: av.Packet):
-    """
-    Check if the packet is in Annex B format.
-    This is typically used for H.264 streams.
-    """
-    # Check if the packet starts with the Annex B start code
-    data = bytes(packet)  # get the raw packet bytes
-    if not (data.startswith(b"\x00\x00\x00\x01") or data.startswith(b"\x00\x00\x01")):
-        raise NotImplementedError("Only Annex B format is supported for H.264/H.265 streams.")
+def publish_network_info(topic, camera: ONVIFCamera, entity_path: str):
+    try:
+        interfaces = get_network_interfaces(camera)
+    except SoapError as e:
+        logger.warning(f"Camera rejected GetNetworkInterfaces: {e}")
+        return
 
+    header = Header(entity_path=entity_path)
+    header.timestamp.FromDatetime(datetime.now())
+    body = json.dumps({"interfaces": [asdict(interface) for interface in interfaces]})
+    topic.publish(PlainText(header=header, body=body))
 
-def main():
-    make87.initialize()
-    topic = make87.get_publisher(name="VIDEO_DATA", message_type=FrameAny)
 
-    onvif_url = make87.resolve_peripheral_name("ONVIF_DEVICE")
-    username, password = (
-        make87.get_config_value("ONVIF_USERNAME"),
-        make87.get_config_value("ONVIF_PASSWORD"),
-    )
-    profile_index = make87.get_config_value("PROFILE_INDEX", default="0", decode=int)
+def metrics_publisher(topic, entity_path: str) -> Callable[[StreamMetrics], None]:
+    def publish(metrics: StreamMetrics):
+        header = Header(entity_path=entity_path)
+        header.timestamp.FromDatetime(datetime.now())
+        topic.publish(PlainText(header=header, body=json.dumps(asdict(metrics))))
 
-    protocol, ip, port, url_suffix = parse_url(onvif_url)
+    return publish
 
-    camera = ONVIFCamera(host=ip, port=port, user=username, passwd=password)
 
-    # --- Get the streaming URI via the Media service ---
-    # Create the media service client.
-    media_service = camera.create_media_service()
-
-    # Retrieve available profiles (video configurations)
-    profiles = media_service.GetProfiles()
-    if len(profiles) < profile_index + 1:
-        raise Exception(f"No profile with index {profile_index} available.")
-    default_profile = profiles[profile_index]
-
-    logging.debug("Selected Profile:")
-    logging.debug(default_profile)
-
-    # Create a request to get the stream URI.
-    stream_req = media_service.create_type("GetStreamUri")
-    stream_req.ProfileToken = default_profile.token
-    stream_req.StreamSetup = {
-        "Stream": "RTP-Unicast",
-        "Transport": {"Protocol": "RTSP"},
-    }
+def stream_info_publisher(topic, entity_path: str) -> Callable[[VideoStreamInfo], None]:
+    def publish(info: VideoStreamInfo):
+        logger.info(f"Stream info: {info.codec} {info.width}x{info.height}, profile-level-id {info.profile_level_id}")
+        header = Header(entity_path=entity_path)
+        header.timestamp.FromDatetime(datetime.now())
+        topic.publish(PlainText(header=header, body=json.dumps(info.to_dict())))
 
-    stream_uri = media_service.GetStreamUri(stream_req).Uri
-    logging.info(f"Stream URI: {stream_uri}")
+    return publish
 
-    _, _, _, entity_path = parse_url(url=stream_uri)
-    stream_uri = inject_rtsp_auth(uri=stream_uri, username=username, password=password)
-    with av.open(stream_uri) as container:
-        stream_start = datetime.now()  # Reference timestamp
 
-        # Find the requested video stream
-        video_streams = container.streams.video
-        if len(video_streams) == 0:
-            raise ValueError("No video stream not found.")
+def select_profile(profiles: list[MediaProfile], camera_config: CameraConfig) -> MediaProfile:
+    profile_index = camera_config.profile_index
+    if profile_index is not None:
+        if len(profiles) < profile_index + 1:
+            raise OnvifError(f"No profile with index {profile_index} available.")
+        return profiles[profile_index]
 
-        video_stream = video_streams[0]
+    profile = select_preferred_profile(profiles, camera_config.profile_preference)
+    if profile is None:
+        raise OnvifError(
+            f"No profile matches {camera_config.profile_preference}, set PROFILE_INDEX to pick one explicitly."
+        )
+    return profile
 
-        # Print stream information
-        stream_info = {
-            "Index": video_stream.index,
-            "Codec": video_stream.codec_context.name,
-            "Resolution": f"{video_stream.width}x{video_stream.height}",
-            "Pixel Format": video_stream.pix_fmt,
-            "Frame Rate": str(video_stream.average_rate),
-        }
-        logger.info(f"Stream Attributes: {stream_info}")
 
-        # Validate codec support
-        codec_name = video_stream.codec_context.name
-        if codec_name not in {"h264", "hevc", "av1"}:
-            raise ValueError(f"Unsupported codec: {codec_name}")
+@dataclass
+class SelectedProfiles:
+    """
+    The profile tokens streamed in the last session. Profiles are selected anew on every session,
+    so a reconfigured encoder doesn't leave the driver with a stale token.
+    """
 
-        # Stream metadata
-        start_pts = video_stream.start_time or 0  # Handle missing start_time
-        time_base = float(video_stream.time_base)
-        width, height = video_stream.width, video_stream.height
+    main: Optional[str] = None
+    sub: Optional[str] = None
+
+    def update(self, stream: str, profile: Optional[MediaProfile]):
+        token = profile.token if profile is not None else None
+        previous = getattr(self, stream)
+        if previous is not None and token != previous:
+            logger.info(f"Profile of the {stream} stream changed from {previous} to {token}.")
+        setattr(self, stream, token)
+
+
+def select_sub_profile(
+    profiles: list[MediaProfile], main_profile: MediaProfile, profile_index: Optional[int]
+) -> Optional[MediaProfile]:
+    if profile_index is not None:
+        if len(profiles) < profile_index + 1:
+            raise OnvifError(f"No profile with index {profile_index} available for the sub stream.")
+        return profiles[profile_index]
+
+    profile = select_lowest_resolution_video(profiles, exclude=main_profile)
+    if profile is None:
+        logger.info("Camera has no second video profile, only a single stream is available.")
+    return profile
+
+
+async def resolve_stream(media_service, profile: MediaProfile, camera_config: CameraConfig) -> tuple[str, str]:
+    """The authenticated RTSP URI of a profile and the entity path its frames are published under."""
+    stream_uri = await asyncio.to_thread(get_stream_uri, media_service, profile_token=profile.token)
+    logger.info(f"Stream URI of profile {profile.name}: {stream_uri}")
+
+    _, _, _, stream_path = parse_url(url=stream_uri)
+    credentials = camera_config.credentials
+    stream_uri = inject_rtsp_auth(uri=stream_uri, username=credentials.username, password=credentials.password.reveal())
+    return stream_uri, f"{camera_config.entity_path}/{stream_path.removeprefix('/')}"
+
+
+async def run_sub_stream(
+    topic,
+    metrics_topic,
+    stream_info_topic,
+    media_service,
+    main_profile: MediaProfile,
+    camera_config: CameraConfig,
+    backoff: BackoffPolicy,
+    settings: RtspSettings,
+    selected: SelectedProfiles,
+    status: CameraStatus,
+):
+    """
+    Publish the sub stream until the session ends. It reconnects on its own,
+    so a stalled or broken sub stream never interrupts the main stream.
+    """
 
-        validated_annex_b = False
+    async def open_sub_stream():
+        # The profile is looked up again on every attempt, in case the camera's encoders were reconfigured.
+        profiles = await asyncio.to_thread(get_profiles, media_service)
+        profile = select_sub_profile(profiles, main_profile, camera_config.sub_profile_index)
+        if profile is None:
+            raise OnvifError("The sub stream profile disappeared.")
+        selected.update("sub", profile)
+        stream_uri, entity_path = await resolve_stream(media_service, profile, camera_config)
+        await run_stream(
+            topic,
+            stream_uri,
+            entity_path,
+            on_streaming=lambda: None,
+            on_frame=lambda size: status.frame_published(size, stream="sub"),
+            on_metrics=fan_out(
+                [
+                    metrics_publisher(metrics_topic, entity_path),
+                    lambda metrics: status.stream_measured("sub", metrics.fps),
+                ]
+            ),
+            settings=settings,
+            on_stall=status.stream_stalled,
+            on_stream_info=stream_info_publisher(stream_info_topic, entity_path),
+        )
 
-        for packet in container.demux(video_stream):
-            if packet.dts is None:
-                continue  # Skip invalid frames
+    try:
+        while True:
+            await with_backoff(open_sub_stream, backoff)
+            logger.info("Sub stream ended, reopening it.")
+    except OnvifError as e:
+        logger.error(f"Sub stream stopped: {e}")
+
+
+@dataclass
+class CameraControllers:
+    """Handlers for the command topics, re-attached to the camera on every session."""
+
+    ptz: PtzController
+    imaging: ImagingController
+    snapshot: SnapshotResponder
+    relay: RelayController
+    osd: OsdController
+    maintenance: MaintenanceController
+
+
+async def run_session(
+    camera_config: CameraConfig,
+    config: DriverConfig,
+    topics: dict,
+    controllers: CameraControllers,
+    connection_state: ConnectionStatePublisher,
+    status: CameraStatus,
+    selected: SelectedProfiles,
+):
+    """Connect to the camera and stream from it until the stream ends."""
+    camera_path = camera_config.entity_path
+    # GetCapabilities on connect is already authenticated, so rejected credentials show from here.
+    connection_state.set(ConnectionState.AUTHENTICATING)
+    camera = await asyncio.to_thread(
+        connect,
+        host=camera_config.host,
+        port=camera_config.port,
+        credentials=camera_config.credentials,
+        max_concurrent_requests=camera_config.max_concurrent_requests or config.max_concurrent_requests,
+    )
+    await asyncio.to_thread(sync_time, camera, config.time_sync)
+    connection_state.set(ConnectionState.DISCOVERING)
+    await asyncio.to_thread(publish_device_information, topics["DEVICE_INFO"], camera, entity_path=camera_path)
+    await asyncio.to_thread(publish_network_info, topics["NETWORK_INFO"], camera, entity_path=camera_path)
+    services = await asyncio.to_thread(discover_services, camera)
 
-            if not validated_annex_b:
-                if codec_name in {"h264", "hevc"}:
-                    # Check for Annex B format
-                    check_annex_b_format(packet)
-                validated_annex_b = True
+    # --- Get the streaming URI via the Media service ---
+    media_service = await asyncio.to_thread(create_media_service, camera, services)
+
+    # Retrieve available profiles (video configurations) and select by the configured criteria, never a cached token
+    profiles = await asyncio.to_thread(get_profiles, media_service)
+    default_profile = select_profile(profiles, camera_config)
+    sub_profile = select_sub_profile(profiles, default_profile, camera_config.sub_profile_index)
+    selected.update("main", default_profile)
+
+    logger.debug(f"Selected profile: {default_profile}, sub stream profile: {sub_profile}")
+
+    rtsp = config.rtsp
+    if camera_config.rtsp_transport is not None:
+        rtsp = replace(rtsp, transport=camera_config.rtsp_transport)
+
+    session_tasks = []
+    restart = asyncio.get_running_loop().create_future()
+    try:
+        await asyncio.to_thread(controllers.maintenance.attach, camera, on_restart=restart_signal(restart))
+        session_tasks = await start_session_tasks(
+            camera, media_service, default_profile, camera_config, config, topics, controllers
+        )
+        if sub_profile is not None:
+            sub_stream = run_sub_stream(
+                topics["VIDEO_DATA_SUB"],
+                topics["STREAM_METRICS"],
+                topics["STREAM_INFO"],
+                media_service,
+                default_profile,
+                camera_config,
+                backoff=config.backoff,
+                settings=replace(rtsp, publish_fps=config.sub_publish_fps),
+                selected=selected,
+                status=status,
+            )
+            session_tasks.append(asyncio.create_task(sub_stream))
+
+        stream_uri, entity_path = await resolve_stream(media_service, default_profile, camera_config)
+        stream = run_stream(
+            topics["VIDEO_DATA"],
+            stream_uri,
+            entity_path,
+            on_streaming=lambda: on_streaming(connection_state, status),
+            on_frame=status.frame_published,
+            on_metrics=fan_out(
+                [
+                    metrics_publisher(topics["STREAM_METRICS"], entity_path),
+                    lambda metrics: status.stream_measured("main", metrics.fps),
+                ]
+            ),
+            settings=rtsp,
+            on_stall=status.stream_stalled,
+            audio_topic=topics["AUDIO_FRAME"],
+            on_stream_info=stream_info_publisher(topics["STREAM_INFO"], entity_path),
+        )
+        stream_task = asyncio.create_task(stream)
+        session_tasks.append(stream_task)
+        await asyncio.wait([stream_task, restart], return_when=asyncio.FIRST_COMPLETED)
+        if restart.done():
+            raise CameraRestarting(restart.result())
+        stream_task.result()
+    finally:
+        controllers.ptz.detach()
+        controllers.imaging.detach()
+        controllers.snapshot.detach()
+        controllers.relay.detach()
+        controllers.osd.detach()
+        controllers.maintenance.detach()
+        for task in session_tasks:
+            task.cancel()
+        # Wait for the tasks to clean up on the camera, e.g. unsubscribe from events and tear down the sub stream.
+        await asyncio.gather(*session_tasks, return_exceptions=True)
+
+
+async def start_session_tasks(
+    camera: ONVIFCamera,
+    media_service,
+    profile: MediaProfile,
+    camera_config: CameraConfig,
+    config: DriverConfig,
+    topics: dict,
+    controllers: CameraControllers,
+) -> list[asyncio.Task]:
+    """Attach the command controllers and start the tasks that run alongside the video stream for the session."""
+    camera_path = camera_config.entity_path
+    tasks = []
+    try:
+        if supports_ptz(camera):
+            await asyncio.to_thread(
+                controllers.ptz.attach,
+                camera,
+                profile_token=profile.token,
+                configuration_token=profile.ptz_configuration_token,
+            )
+            if config.ptz_status_interval > 0:
+                ptz_status = poll_ptz_status(
+                    controllers.ptz, topics["PTZ_STATUS"], interval=config.ptz_status_interval, entity_path=camera_path
+                )
+                tasks.append(asyncio.create_task(ptz_status))
+        else:
+            logger.info("Camera does not expose a PTZ service, ignoring PTZ commands.")
+
+        if supports_imaging(camera) and profile.video_source_token is not None:
+            try:
+                await asyncio.to_thread(
+                    controllers.imaging.attach, camera, video_source_token=profile.video_source_token
+                )
+            except SoapError as e:
+                logger.warning(f"Imaging service unavailable, ignoring imaging commands: {e}")
+        else:
+            logger.info("Camera does not expose an imaging service, ignoring imaging commands.")
+
+        await asyncio.to_thread(controllers.relay.attach, camera)
+
+        if profile.video_source_configuration_token is not None:
+            try:
+                await asyncio.to_thread(
+                    controllers.osd.attach, media_service, configuration_token=profile.video_source_configuration_token
+                )
+            except SoapError as e:
+                logger.info(f"Camera does not support OSD overlays, ignoring OSD commands: {e}")
+
+        if supports_events(camera):
+            tasks.append(asyncio.create_task(pull_events(camera, topics["EVENTS"], entity_path=camera_path)))
+        else:
+            logger.info("Camera does not expose an event service, not subscribing to events.")
+
+        try:
+            snapshot_uri = await asyncio.to_thread(get_snapshot_uri, media_service, profile_token=profile.token)
+            controllers.snapshot.attach(snapshot_uri)
+        except SoapError as e:
+            logger.warning(f"Camera has no snapshot URI, snapshots are unavailable: {e}")
+            snapshot_uri = None
+
+        if config.snapshot_interval > 0 and snapshot_uri is not None:
+            snapshots = poll_snapshots(
+                topics["SNAPSHOT"],
+                snapshot_uri,
+                camera_config.credentials,
+                interval=config.snapshot_interval,
+                entity_path=camera_path,
+            )
+            tasks.append(asyncio.create_task(snapshots))
+    except BaseException:
+        for task in tasks:
+            task.cancel()
+        raise
+    return tasks
+
+
+def restart_signal(restart: asyncio.Future) -> Callable[[str], None]:
+    """A thread-safe callback resolving `restart`, for controllers that take the camera down on purpose."""
+    loop = asyncio.get_running_loop()
+
+    def signal_restart(reason: str):
+        loop.call_soon_threadsafe(lambda: restart.done() or restart.set_result(reason))
+
+    return signal_restart
+
+
+def on_streaming(connection_state: ConnectionStatePublisher, status: CameraStatus):
+    status.streaming()
+    connection_state.set(ConnectionState.STREAMING)
+
+
+def on_retry(connection_state: ConnectionStatePublisher, status: CameraStatus, error: OnvifError):
+    status.failed()
+    connection_state.set(ConnectionState.RECONNECTING, reason=str(error))
+
+
+async def run_camera(
+    camera_config: CameraConfig,
+    config: DriverConfig,
+    topics: dict,
+    controllers: CameraControllers,
+    status: CameraStatus,
+):
+    """Keep one camera streaming, independently of all other cameras."""
+    set_camera_context(camera_config.id, xaddr=camera_config.address)
+    connection_state = ConnectionStatePublisher(
+        topics["CONNECTION_STATE"], entity_path=camera_config.entity_path, on_change=status.connection_changed
+    )
+    selected = SelectedProfiles()
+
+    try:
+        while True:
+            try:
+                await with_backoff(
+                    lambda: run_session(camera_config, config, topics, controllers, connection_state, status, selected),
+                    config.backoff,
+                    on_retry=lambda e: on_retry(connection_state, status, e),
+                )
+            except CameraRestarting as e:
+                # The camera is expected to disappear; don't hammer it with reconnects while it boots.
+                status.disconnected()
+                connection_state.set(ConnectionState.RECONNECTING, reason=str(e))
+                logger.info(f"Reconnecting in {config.reboot_wait}s.")
+                await asyncio.sleep(config.reboot_wait)
+                continue
+            status.disconnected()
+            connection_state.set(ConnectionState.RECONNECTING, reason="stream ended")
+    except OnvifError as e:
+        # Non-retryable errors like rejected credentials end here instead of reconnecting forever.
+        status.failed()
+        connection_state.set(ConnectionState.FAILED, reason=str(e))
+        logger.error(f"Camera {camera_config.id} stopped: {e}")
+
+
+def camera_metrics(statuses: dict[str, CameraStatus], controllers: dict[str, CameraControllers]) -> list[CameraMetrics]:
+    return [
+        replace(status.metrics(), ptz_commands=controllers[camera_id].ptz.commands_processed)
+        for camera_id, status in statuses.items()
+    ]
+
+
+def fan_out(handlers: list[Callable]) -> Callable:
+    def dispatch(message):
+        for handler in handlers:
+            # One camera's failing controller must not keep the command from the others.
+            try:
+                handler(message)
+            except Exception:
+                logger.exception("Handling an inbound message failed")
+
+    return dispatch
+
+
+async def main():
+    make87.initialize()
+    setup_logging(load_log_level())
+    config = load_config()
+    if config.bootstrap is not None:
+        bootstrapped = await bootstrap_cameras(config.bootstrap, config.rtsp, config.discovery_timeout)
+        if not bootstrapped:
+            raise ConfigError(
+                "Bootstrap found no camera accepting any of the BOOTSTRAP_CREDENTIALS"
+                f" within {config.bootstrap.timeout:g}s."
+            )
+        publish_bootstrap_config(get_publisher(name="BOOTSTRAP_CONFIG", message_type=PlainText), bootstrapped)
+        config.cameras = [camera.camera for camera in bootstrapped]
+
+    # Every camera keeps worker threads busy with blocking streaming and long-polling calls.
+    asyncio.get_running_loop().set_default_executor(ThreadPoolExecutor(max_workers=8 * len(config.cameras) + 4))
+    topics = {
+        "VIDEO_DATA": get_publisher(name="VIDEO_DATA", message_type=FrameAny),
+        "VIDEO_DATA_SUB": get_publisher(name="VIDEO_DATA_SUB", message_type=FrameAny),
+        "AUDIO_FRAME": get_publisher(name="AUDIO_FRAME", message_type=PlainText),
+        "DISCOVERED_DEVICES": get_publisher(name="DISCOVERED_DEVICES", message_type=PlainText),
+        "DEVICE_INFO": get_publisher(name="DEVICE_INFO", message_type=PlainText),
+        "NETWORK_INFO": get_publisher(name="NETWORK_INFO", message_type=PlainText),
+        "CONNECTION_STATE": get_publisher(name="CONNECTION_STATE", message_type=PlainText),
+        "SNAPSHOT": get_publisher(name="SNAPSHOT", message_type=ImageJPEG),
+        "SNAPSHOT_RESPONSE": get_publisher(name="SNAPSHOT_RESPONSE", message_type=ImageJPEG),
+        "EVENTS": get_publisher(name="EVENTS", message_type=PlainText),
+        "HEALTH": get_publisher(name="HEALTH", message_type=PlainText),
+        "PTZ_STATUS": get_publisher(name="PTZ_STATUS", message_type=PlainText),
+        "STREAM_METRICS": get_publisher(name="STREAM_METRICS", message_type=PlainText),
+        "STREAM_INFO": get_publisher(name="STREAM_INFO", message_type=PlainText),
+    }
 
-            # Compute timestamps
-            relative_timestamp = (packet.pts - start_pts) * time_base
-            absolute_timestamp = stream_start + timedelta(seconds=relative_timestamp)
+    discovery = asyncio.create_task(
+        asyncio.to_thread(publish_discovered_devices, topics["DISCOVERED_DEVICES"], timeout=config.discovery_timeout)
+    )
 
-            header = Header(entity_path=f"/camera/{entity_path.removeprefix('/')}")
-            header.timestamp.FromDatetime(absolute_timestamp)
+    controllers = {
+        camera.id: CameraControllers(
+            ptz=PtzController(
+                camera_id=camera.id,
+                command_timeout=config.ptz_command_timeout,
+                command_interval=config.ptz_command_interval,
+            ),
+            imaging=ImagingController(camera_id=camera.id),
+            snapshot=SnapshotResponder(
+                camera.id, topics["SNAPSHOT_RESPONSE"], camera.credentials, entity_path=camera.entity_path
+            ),
+            relay=RelayController(camera_id=camera.id),
+            osd=OsdController(camera_id=camera.id),
+            maintenance=MaintenanceController(camera.id, allow_factory_default=config.allow_factory_default),
+        )
+        for camera in config.cameras
+    }
+    get_subscriber(name="PTZ_COMMAND", message_type=PlainText).subscribe(
+        fan_out([controller.ptz.handle_command for controller in controllers.values()])
+    )
+    get_subscriber(name="IMAGING_COMMAND", message_type=PlainText).subscribe(
+        fan_out([controller.imaging.handle_command for controller in controllers.values()])
+    )
+    get_subscriber(name="SNAPSHOT_REQUEST", message_type=PlainText).subscribe(
+        fan_out([controller.snapshot.handle_request for controller in controllers.values()])
+    )
+    get_subscriber(name="RELAY_COMMAND", message_type=PlainText).subscribe(
+        fan_out([controller.relay.handle_command for controller in controllers.values()])
+    )
+    get_subscriber(name="OSD_COMMAND", message_type=PlainText).subscribe(
+        fan_out([controller.osd.handle_command for controller in controllers.values()])
+    )
+    get_subscriber(name="MAINTENANCE_COMMAND", message_type=PlainText).subscribe(
+        fan_out([controller.maintenance.handle_command for controller in controllers.values()])
+    )
 
-            # Encode and publish the frame
-            topic.publish(encode_frame(codec_name, header, packet, width, height))
+    statuses = {camera.id: CameraStatus(camera.id, entity_path=camera.entity_path) for camera in config.cameras}
+    metrics_server = None
+    if config.metrics_enabled:
+        metrics_server = MetricsServer(
+            config.metrics_port, collect=lambda: render_metrics(camera_metrics(statuses, controllers))
+        )
+        try:
+            metrics_server.start()
+        except OSError as e:
+            raise ConfigError(f"Cannot serve metrics on port {config.metrics_port}: {e}") from e
+    health = asyncio.create_task(report_health(topics["HEALTH"], list(statuses.values()), config.health_interval))
+
+    shutdown = asyncio.Event()
+    for signal_number in (signal.SIGTERM, signal.SIGINT):
+        asyncio.get_running_loop().add_signal_handler(signal_number, shutdown.set)
+
+    camera_tasks = [
+        asyncio.create_task(run_camera(camera, config, topics, controllers[camera.id], statuses[camera.id]))
+        for camera in config.cameras
+    ]
+    shutdown_requested = asyncio.create_task(shutdown.wait())
+    try:
+        await asyncio.wait(
+            [asyncio.gather(*camera_tasks, return_exceptions=True), shutdown_requested],
+            return_when=asyncio.FIRST_COMPLETED,
+        )
+        if shutdown.is_set():
+            await shut_down(camera_tasks, timeout=config.shutdown_timeout)
+
+        for camera, task in zip(config.cameras, camera_tasks):
+            if task.done() and not task.cancelled() and task.exception() is not None:
+                logger.error(f"Camera {camera.id} crashed", exc_info=task.exception())
+    finally:
+        discovery.cancel()
+        health.cancel()
+        shutdown_requested.cancel()
+        for status in statuses.values():
+            status.disconnected()
+            await asyncio.to_thread(publish_health, topics["HEALTH"], status)
+        if metrics_server is not None:
+            await asyncio.to_thread(metrics_server.close)
+
+    if shutdown.is_set():
+        # Worker threads may still be blocked in camera I/O (event long polls, stalled sockets) and would keep the
+        # interpreter alive. Everything that holds resources on the cameras has been closed at this point.
+        logging.shutdown()
+        os._exit(0)
+
+
+async def shut_down(camera_tasks: list[asyncio.Task], timeout: float):
+    """Stop all cameras, giving them `timeout` seconds to tear down RTSP sessions and event subscriptions."""
+    logger.info("Shutting down.")
+    for task in camera_tasks:
+        task.cancel()
+    _, pending = await asyncio.wait(camera_tasks, timeout=timeout)
+    if pending:
+        logger.warning(f"{len(pending)} camera(s) did not shut down within {timeout}s.")
 
 
 if __name__ == "__main__":
-    main()
+    setup_logging("INFO")
+    try:
+        asyncio.run(main())
+    except OnvifError as e:
+        logger.error(f"Driver stopped: {e}")
+        sys.exit(1)
@@ -0,0 +1,329 @@
+import json
+import logging
+import threading
+from dataclasses import dataclass, field
+from typing import Optional, Union
+
+from make87_messages.text.text_plain_pb2 import PlainText
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, ParseError, onvif_errors
+from app.logs import camera_context
+from app.media2 import MEDIA2_NAMESPACE, Media2Service
+from app.services import Services
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+MEDIA1_NAMESPACE = "http://www.onvif.org/ver10/media/wsdl"
+SCHEMA_NAMESPACE = "http://www.onvif.org/ver10/schema"
+PROFILE_ORDERS = ("highest", "lowest")
+# Encodings a preference can be restricted to; without one, both are candidates.
+PROFILE_ENCODINGS = {"h264": "H264", "h265": "H265"}
+
+
+@dataclass
+class MediaProfile:
+    token: str
+    name: str
+    resolution: Optional[tuple[int, int]] = None
+    encoding: Optional[str] = None
+    framerate: Optional[float] = None
+    ptz_configuration_token: Optional[str] = None
+    video_source_token: Optional[str] = None
+    video_source_configuration_token: Optional[str] = None
+
+    @property
+    def pixel_count(self) -> int:
+        return self.resolution[0] * self.resolution[1] if self.resolution else 0
+
+
+def create_media_service(camera: ONVIFCamera, services: Services):
+    """
+    Create a media service client at the address the camera advertised. Media1 is preferred, since onvif-zeep
+    ships its WSDL and OSD overlays need it; Media2-only cameras get a `Media2Service`.
+    """
+    if MEDIA1_NAMESPACE in services:
+        with onvif_errors("create media service"):
+            return create_service(camera, "media")
+    if MEDIA2_NAMESPACE in services:
+        return Media2Service(camera, services[MEDIA2_NAMESPACE].xaddr)
+    raise OnvifError("Camera does not expose an ONVIF media service.")
+
+
+def _media2_uri(response, operation: str) -> str:
+    uri = response.findtext(f"{{{MEDIA2_NAMESPACE}}}Uri")
+    if not uri:
+        raise ParseError(f"{operation}: response has no Uri")
+    return uri
+
+
+def get_stream_uri(media_service, profile_token: str) -> str:
+    if isinstance(media_service, Media2Service):
+        response = call(media_service, "GetStreamUri", {"Protocol": "RTSP", "ProfileToken": profile_token})
+        return _media2_uri(response, "GetStreamUri")
+
+    request = {
+        "ProfileToken": profile_token,
+        "StreamSetup": {"Stream": "RTP-Unicast", "Transport": {"Protocol": "RTSP"}},
+    }
+    return call(media_service, "GetStreamUri", request).Uri
+
+
+def get_snapshot_uri(media_service, profile_token: str) -> str:
+    if isinstance(media_service, Media2Service):
+        return _media2_uri(call(media_service, "GetSnapshotUri", {"ProfileToken": profile_token}), "GetSnapshotUri")
+    return call(media_service, "GetSnapshotUri", {"ProfileToken": profile_token}).Uri
+
+
+def get_profiles(media_service) -> list[MediaProfile]:
+    """
+    List the media profiles of the camera.
+    Profiles without a video encoder configuration (audio-only, metadata) keep their video fields as `None`.
+    """
+    if isinstance(media_service, Media2Service):
+        response = call(media_service, "GetProfiles", {"Type": ["VideoSource", "VideoEncoder", "PTZ"]})
+        return [_media2_profile(profile) for profile in response.iterfind(f"{{{MEDIA2_NAMESPACE}}}Profiles")]
+
+    onvif_profiles = call(media_service, "GetProfiles")
+
+    profiles = []
+    for profile in onvif_profiles:
+        media_profile = MediaProfile(token=profile.token, name=profile.Name)
+
+        encoder = getattr(profile, "VideoEncoderConfiguration", None)
+        if encoder is not None:
+            if encoder.Resolution is not None:
+                media_profile.resolution = (encoder.Resolution.Width, encoder.Resolution.Height)
+            media_profile.encoding = encoder.Encoding
+            if encoder.RateControl is not None and encoder.RateControl.FrameRateLimit is not None:
+                media_profile.framerate = float(encoder.RateControl.FrameRateLimit)
+
+        source = getattr(profile, "VideoSourceConfiguration", None)
+        if source is not None:
+            media_profile.video_source_token = source.SourceToken
+            media_profile.video_source_configuration_token = source.token
+
+        ptz_configuration = getattr(profile, "PTZConfiguration", None)
+        if ptz_configuration is not None:
+            media_profile.ptz_configuration_token = ptz_configuration.token
+
+        profiles.append(media_profile)
+
+    return profiles
+
+
+def _media2_profile(profile) -> MediaProfile:
+    """A `tr2:Profiles` element, whose configurations use the ver10 schema types below `tr2:Configurations`."""
+    tt = f"{{{SCHEMA_NAMESPACE}}}"
+    configurations = f"{{{MEDIA2_NAMESPACE}}}Configurations/{{{MEDIA2_NAMESPACE}}}"
+    media_profile = MediaProfile(token=profile.get("token"), name=profile.findtext(f"{{{MEDIA2_NAMESPACE}}}Name"))
+
+    encoder = profile.find(f"{configurations}VideoEncoder")
+    if encoder is not None:
+        width, height = encoder.findtext(f"{tt}Resolution/{tt}Width"), encoder.findtext(f"{tt}Resolution/{tt}Height")
+        if width and height:
+            media_profile.resolution = (int(width), int(height))
+        media_profile.encoding = encoder.findtext(f"{tt}Encoding")
+        framerate = encoder.findtext(f"{tt}RateControl/{tt}FrameRateLimit")
+        if framerate:
+            media_profile.framerate = float(framerate)
+
+    source = profile.find(f"{configurations}VideoSource")
+    if source is not None:
+        media_profile.video_source_token = source.findtext(f"{tt}SourceToken")
+        media_profile.video_source_configuration_token = source.get("token")
+
+    ptz_configuration = profile.find(f"{configurations}PTZ")
+    if ptz_configuration is not None:
+        media_profile.ptz_configuration_token = ptz_configuration.get("token")
+
+    return media_profile
+
+
+def select_lowest_resolution_video(profiles: list[MediaProfile], exclude: MediaProfile) -> Optional[MediaProfile]:
+    """The smallest H.264/H.265 profile other than `exclude`, used as the sub stream."""
+    candidates = [
+        profile
+        for profile in profiles
+        if profile.token != exclude.token and profile.resolution is not None and profile.encoding in {"H264", "H265"}
+    ]
+    if not candidates:
+        return None
+    return min(candidates, key=lambda profile: profile.pixel_count)
+
+
+@dataclass
+class ProfilePreference:
+    """Which profile to stream, e.g. `highest_h264` or `lowest`, matched against the profiles of every session."""
+
+    highest: bool = True
+    # "H264" or "H265"; `None` accepts either.
+    encoding: Optional[str] = "H264"
+
+    def __str__(self) -> str:
+        order = PROFILE_ORDERS[0] if self.highest else PROFILE_ORDERS[1]
+        encoding = next((name for name, value in PROFILE_ENCODINGS.items() if value == self.encoding), None)
+        return f"{order}_{encoding}" if encoding else order
+
+
+def parse_profile_preference(value: str) -> ProfilePreference:
+    order, _, encoding = value.strip().lower().partition("_")
+    if order not in PROFILE_ORDERS or (encoding and encoding not in PROFILE_ENCODINGS):
+        raise ValueError(f"expected one of {', '.join(PROFILE_ORDERS)}, optionally followed by _h264 or _h265")
+    return ProfilePreference(highest=order == "highest", encoding=PROFILE_ENCODINGS.get(encoding))
+
+
+def select_preferred_profile(profiles: list[MediaProfile], preference: ProfilePreference) -> Optional[MediaProfile]:
+    encodings = {preference.encoding} if preference.encoding else set(PROFILE_ENCODINGS.values())
+    candidates = [profile for profile in profiles if profile.encoding in encodings]
+    if not candidates:
+        return None
+    pick = max if preference.highest else min
+    return pick(candidates, key=lambda profile: profile.pixel_count)
+
+
+@dataclass
+class OsdOptions:
+    # Named positions (UpperLeft, LowerRight, ...) and "Custom" for explicit coordinates.
+    positions: set[str] = field(default_factory=set)
+    text_types: set[str] = field(default_factory=set)
+    # Not all cameras report a limit.
+    max_text_length: Optional[int] = None
+
+
+# A named position, or custom `(x, y)` coordinates in [-1, 1].
+OsdPosition = Union[str, tuple[float, float]]
+
+
+def get_osd_options(media_service, configuration_token: str) -> OsdOptions:
+    options = call(media_service, "GetOSDOptions", {"ConfigurationToken": configuration_token})
+
+    text_option = options.TextOption
+    max_length = getattr(text_option, "MaxLength", None) if text_option is not None else None
+    return OsdOptions(
+        positions=set(options.PositionOption or []),
+        text_types=set(text_option.Type or []) if text_option is not None else set(),
+        max_text_length=int(max_length) if max_length is not None else None,
+    )
+
+
+def get_text_osds(media_service, configuration_token: str) -> list:
+    osds = call(media_service, "GetOSDs", {"ConfigurationToken": configuration_token})
+    return [osd for osd in osds or [] if osd.Type == "Text" and osd.TextString is not None]
+
+
+def _osd(configuration_token: str, text: str, position: OsdPosition, token: str = "") -> dict:
+    if isinstance(position, tuple):
+        osd_position = {"Type": "Custom", "Pos": {"x": position[0], "y": position[1]}}
+    else:
+        osd_position = {"Type": position}
+    return {
+        "token": token,
+        "VideoSourceConfigurationToken": configuration_token,
+        "Type": "Text",
+        "Position": osd_position,
+        "TextString": {"Type": "Plain", "PlainText": text},
+    }
+
+
+def set_text_overlay(
+    media_service, configuration_token: str, text: str, position: OsdPosition, osd_token: Optional[str] = None
+) -> str:
+    """Show `text` at `position`, updating the overlay `osd_token` or creating one. Returns the overlay token."""
+    if osd_token is not None:
+        call(media_service, "SetOSD", {"OSD": _osd(configuration_token, text, position, token=osd_token)})
+        return osd_token
+    return call(media_service, "CreateOSD", {"OSD": _osd(configuration_token, text, position)})
+
+
+def delete_osd(media_service, osd_token: str):
+    call(media_service, "DeleteOSD", {"OSDToken": osd_token})
+
+
+class OsdController:
+    """
+    Maintains one plain-text overlay from commands like `{"text": "ALARM", "position": "UpperLeft"}`;
+    `"position"` may also be `{"x": -0.9, "y": 0.9}`, and an empty text removes the overlay.
+    Text longer than the camera allows is truncated, unsupported positions are rejected.
+    """
+
+    def __init__(self, camera_id: str):
+        self.camera_id = camera_id
+        self.media_service = None
+        self.configuration_token = None
+        self.options = OsdOptions()
+        self.osd_token = None
+        self._lock = threading.Lock()
+
+    def attach(self, media_service, configuration_token: str):
+        options = get_osd_options(media_service, configuration_token)
+        # Take over a plain-text overlay we (or the camera's UI) created before, instead of stacking new ones.
+        osds = get_text_osds(media_service, configuration_token)
+        plain_osds = [osd for osd in osds if osd.TextString.Type == "Plain"]
+
+        with self._lock:
+            self.media_service = media_service
+            self.configuration_token = configuration_token
+            self.options = options
+            self.osd_token = plain_osds[0].token if plain_osds else None
+
+    def detach(self):
+        with self._lock:
+            self.media_service = None
+            self.configuration_token = None
+
+    def handle_command(self, message: PlainText):
+        # Commands arrive on make87 subscriber threads, outside of the camera's task.
+        with camera_context(self.camera_id):
+            self._handle_command(message)
+
+    def _handle_command(self, message: PlainText):
+        try:
+            command = json.loads(message.body)
+            # Commands without a "camera" field address every camera.
+            if command.get("camera", self.camera_id) != self.camera_id:
+                return
+            text = str(command.get("text") or "")
+            position = command.get("position", "UpperLeft")
+            if isinstance(position, dict):
+                position = (float(position["x"]), float(position["y"]))
+                if not all(-1.0 <= value <= 1.0 for value in position):
+                    raise ValueError("custom OSD coordinates must be within [-1, 1]")
+            elif not isinstance(position, str):
+                raise ValueError("position must be a name or {x, y}")
+        except (ValueError, TypeError, AttributeError, KeyError) as e:
+            logger.warning(f"Ignoring malformed OSD command {message.body!r}: {e}")
+            return
+
+        with self._lock:
+            if self.media_service is None:
+                logger.warning("No camera connected, dropping OSD command.")
+                return
+
+            try:
+                if not text:
+                    if self.osd_token is not None:
+                        delete_osd(self.media_service, self.osd_token)
+                        self.osd_token = None
+                    return
+
+                position_type = "Custom" if isinstance(position, tuple) else position
+                if position_type not in self.options.positions:
+                    supported = ", ".join(sorted(self.options.positions)) or "none"
+                    logger.warning(f"Camera does not support OSD position {position_type} (supported: {supported}).")
+                    return
+                if self.options.text_types and "Plain" not in self.options.text_types:
+                    logger.warning("Camera does not support plain-text overlays, ignoring OSD command.")
+                    return
+                max_length = self.options.max_text_length
+                if max_length is not None and len(text) > max_length:
+                    logger.warning(f"OSD text is longer than {max_length} characters, truncating it.")
+                    text = text[:max_length]
+
+                self.osd_token = set_text_overlay(
+                    self.media_service, self.configuration_token, text, position, osd_token=self.osd_token
+                )
+            except OnvifError as e:
+                logger.error(f"OSD command failed: {e}")
@@ -0,0 +1,114 @@
+import logging
+import threading
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from typing import Callable
+
+from app.connection import ConnectionState
+from app.health import CameraMetrics
+
+logger = logging.getLogger(__name__)
+
+CONTENT_TYPE = "text/plain; version=0.0.4; charset=utf-8"
+
+# Name -> type and help text, in the order they are rendered.
+METRICS = {
+    "onvif_camera_frames_published_total": ("counter", "Video frames published."),
+    "onvif_camera_published_bytes_total": ("counter", "Video payload bytes published."),
+    "onvif_camera_fps": ("gauge", "Frame rate the camera delivered over the last metrics interval."),
+    "onvif_camera_reconnects_total": ("counter", "Times the driver started reconnecting to the camera."),
+    "onvif_camera_stream_restarts_total": ("counter", "Streams restarted because no frames arrived."),
+    "onvif_camera_ptz_commands_total": ("counter", "PTZ commands processed."),
+    "onvif_camera_connection_state": ("gauge", "1 for the current connection state of the camera, 0 for the others."),
+}
+
+
+def _label_value(value: str) -> str:
+    return value.replace("\\", "\\\\").replace('"', '\\"').replace("\n", "\\n")
+
+
+def _sample(name: str, labels: dict[str, str], value) -> str:
+    rendered = ",".join(f'{key}="{_label_value(label)}"' for key, label in labels.items())
+    return f"{name}{{{rendered}}} {value}"
+
+
+def render_metrics(cameras: list[CameraMetrics]) -> str:
+    """The metrics of every camera in the Prometheus text exposition format."""
+    samples = {name: [] for name in METRICS}
+    for camera in cameras:
+        camera_label = {"camera": camera.camera_id}
+        for stream, counters in sorted(camera.streams.items()):
+            labels = {**camera_label, "stream": stream}
+            samples["onvif_camera_frames_published_total"].append((labels, counters.frames_published))
+            samples["onvif_camera_published_bytes_total"].append((labels, counters.bytes_published))
+            samples["onvif_camera_fps"].append((labels, round(counters.fps, 3)))
+        samples["onvif_camera_reconnects_total"].append((camera_label, camera.reconnects))
+        samples["onvif_camera_stream_restarts_total"].append((camera_label, camera.stream_restarts))
+        samples["onvif_camera_ptz_commands_total"].append((camera_label, camera.ptz_commands))
+        for state in ConnectionState:
+            labels = {**camera_label, "state": state.value}
+            samples["onvif_camera_connection_state"].append((labels, int(state == camera.connection_state)))
+
+    lines = []
+    for name, (metric_type, help_text) in METRICS.items():
+        lines.append(f"# HELP {name} {help_text}")
+        lines.append(f"# TYPE {name} {metric_type}")
+        lines.extend(_sample(name, labels, value) for labels, value in samples[name])
+    return "\n".join(lines) + "\n"
+
+
+class MetricsServer:
+    """
+    Serves `collect()` at `/metrics` for Prometheus to scrape, from a thread of its own.
+    The port is only bound on `start`, so a driver without metrics enabled opens none.
+    """
+
+    def __init__(self, port: int, collect: Callable[[], str], host: str = "0.0.0.0"):
+        self.host = host
+        self.collect = collect
+        self._requested_port = port
+        self._server = None
+        self._thread = None
+
+    @property
+    def port(self) -> int:
+        return self._server.server_address[1]
+
+    def start(self):
+        self._server = ThreadingHTTPServer((self.host, self._requested_port), self._handler())
+        self._server.daemon_threads = True
+        self._thread = threading.Thread(target=self._server.serve_forever, name="metrics", daemon=True)
+        self._thread.start()
+        logger.info(f"Serving metrics at http://{self.host}:{self.port}/metrics")
+
+    def close(self):
+        if self._server is None:
+            return
+        self._server.shutdown()
+        self._server.server_close()
+        self._thread.join()
+        self._server = None
+
+    def _handler(self):
+        server = self
+
+        class Handler(BaseHTTPRequestHandler):
+            def do_GET(self):
+                if self.path.split("?", 1)[0] != "/metrics":
+                    self.send_error(404)
+                    return
+                try:
+                    body = server.collect().encode("utf-8")
+                except Exception:
+                    logger.exception("Collecting metrics failed")
+                    self.send_error(500)
+                    return
+                self.send_response(200)
+                self.send_header("Content-Type", CONTENT_TYPE)
+                self.send_header("Content-Length", str(len(body)))
+                self.end_headers()
+                self.wfile.write(body)
+
+            def log_message(self, format, *args):
+                pass
+
+        return Handler
@@ -0,0 +1,514 @@
+import asyncio
+import json
+import logging
+import threading
+import time
+from dataclasses import asdict, dataclass, field
+from datetime import datetime
+from typing import Callable, Optional
+
+from make87_messages.core.header_pb2 import Header
+from make87_messages.text.text_plain_pb2 import PlainText
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, SoapError, onvif_errors
+from app.logs import camera_context
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+PTZ_NAMESPACE = "http://www.onvif.org/ver20/ptz/wsdl"
+MOVE_ACTIONS = {"continuous", "absolute", "relative"}
+PRESET_ACTIONS = {"goto", "set", "remove"}
+
+
+@dataclass
+class Preset:
+    token: str
+    name: str
+
+
+@dataclass
+class AxisRange:
+    min: float
+    max: float
+
+
+@dataclass
+class PtzSpace:
+    pan: AxisRange
+    tilt: AxisRange
+    zoom: AxisRange
+
+    def check(self, pan: Optional[float], tilt: Optional[float], zoom: Optional[float]):
+        """Raise `ValueError` if any given coordinate lies outside of this space."""
+        for name, value, axis in (("pan", pan, self.pan), ("tilt", tilt, self.tilt), ("zoom", zoom, self.zoom)):
+            if value is not None and not axis.min <= value <= axis.max:
+                raise ValueError(f"{name} {value} is outside [{axis.min}, {axis.max}]")
+
+
+# The ONVIF generic (normalized) spaces, used when the camera does not report its own.
+def _default_absolute_space() -> PtzSpace:
+    return PtzSpace(pan=AxisRange(-1.0, 1.0), tilt=AxisRange(-1.0, 1.0), zoom=AxisRange(0.0, 1.0))
+
+
+def _default_relative_space() -> PtzSpace:
+    return PtzSpace(pan=AxisRange(-1.0, 1.0), tilt=AxisRange(-1.0, 1.0), zoom=AxisRange(-1.0, 1.0))
+
+
+@dataclass
+class PtzBounds:
+    absolute: PtzSpace = field(default_factory=_default_absolute_space)
+    relative: PtzSpace = field(default_factory=_default_relative_space)
+
+
+@dataclass
+class PtzStatus:
+    pan: Optional[float]
+    tilt: Optional[float]
+    zoom: Optional[float]
+    # IDLE, MOVING or UNKNOWN
+    pan_tilt_move_status: Optional[str]
+    zoom_move_status: Optional[str]
+    utc_time: Optional[str]
+
+    def to_json(self) -> str:
+        return json.dumps(asdict(self))
+
+
+def supports_ptz(camera: ONVIFCamera) -> bool:
+    return PTZ_NAMESPACE in camera.xaddrs
+
+
+def clamp_velocity(name: str, value: float) -> float:
+    if -1.0 <= value <= 1.0:
+        return value
+    clamped = max(-1.0, min(1.0, value))
+    logger.warning(f"PTZ {name} velocity {value} is outside [-1.0, 1.0], clamping to {clamped}.")
+    return clamped
+
+
+def continuous_move(ptz_service, profile_token: str, pan: float, tilt: float, zoom: float):
+    velocity = {
+        "PanTilt": {"x": clamp_velocity("pan", pan), "y": clamp_velocity("tilt", tilt)},
+        "Zoom": {"x": clamp_velocity("zoom", zoom)},
+    }
+    call(ptz_service, "ContinuousMove", {"ProfileToken": profile_token, "Velocity": velocity})
+
+
+def stop(ptz_service, profile_token: str):
+    call(ptz_service, "Stop", {"ProfileToken": profile_token, "PanTilt": True, "Zoom": True})
+
+
+def _vector(pan: Optional[float], tilt: Optional[float], zoom: Optional[float]) -> dict:
+    vector = {}
+    if pan is not None:
+        vector["PanTilt"] = {"x": pan, "y": tilt}
+    if zoom is not None:
+        vector["Zoom"] = {"x": zoom}
+    return vector
+
+
+def absolute_move(ptz_service, profile_token: str, pan: Optional[float], tilt: Optional[float], zoom: Optional[float]):
+    call(ptz_service, "AbsoluteMove", {"ProfileToken": profile_token, "Position": _vector(pan, tilt, zoom)})
+
+
+def relative_move(ptz_service, profile_token: str, pan: Optional[float], tilt: Optional[float], zoom: Optional[float]):
+    call(ptz_service, "RelativeMove", {"ProfileToken": profile_token, "Translation": _vector(pan, tilt, zoom)})
+
+
+def get_status(ptz_service, profile_token: str) -> PtzStatus:
+    status = call(ptz_service, "GetStatus", {"ProfileToken": profile_token})
+
+    position = status.Position
+    pan_tilt = position.PanTilt if position is not None else None
+    zoom = position.Zoom if position is not None else None
+    move_status = status.MoveStatus
+    return PtzStatus(
+        pan=float(pan_tilt.x) if pan_tilt is not None else None,
+        tilt=float(pan_tilt.y) if pan_tilt is not None else None,
+        zoom=float(zoom.x) if zoom is not None else None,
+        pan_tilt_move_status=move_status.PanTilt if move_status is not None else None,
+        zoom_move_status=move_status.Zoom if move_status is not None else None,
+        utc_time=status.UtcTime.isoformat() if status.UtcTime is not None else None,
+    )
+
+
+def get_presets(ptz_service, profile_token: str) -> list[Preset]:
+    presets = call(ptz_service, "GetPresets", {"ProfileToken": profile_token})
+    return [Preset(token=preset.token, name=preset.Name or "") for preset in presets or []]
+
+
+def goto_preset(ptz_service, profile_token: str, preset_token: str, speed: Optional[float] = None):
+    request = {"ProfileToken": profile_token, "PresetToken": preset_token}
+    if speed is not None:
+        request["Speed"] = {"PanTilt": {"x": speed, "y": speed}, "Zoom": {"x": speed}}
+    call(ptz_service, "GotoPreset", request)
+
+
+def set_preset(ptz_service, profile_token: str, name: str, preset_token: Optional[str] = None) -> str:
+    """Store the current position as preset `name`, overwriting `preset_token` if given. Returns the token."""
+    request = {"ProfileToken": profile_token, "PresetName": name}
+    if preset_token is not None:
+        request["PresetToken"] = preset_token
+    return call(ptz_service, "SetPreset", request)
+
+
+def remove_preset(ptz_service, profile_token: str, preset_token: str):
+    call(ptz_service, "RemovePreset", {"ProfileToken": profile_token, "PresetToken": preset_token})
+
+
+def find_preset(presets: list[Preset], name: str) -> Optional[Preset]:
+    """Look a preset up by name, or by token for callers that already know it."""
+    for preset in presets:
+        if preset.name == name:
+            return preset
+    for preset in presets:
+        if preset.token == name:
+            return preset
+    return None
+
+
+def _space_2d(spaces, default: PtzSpace) -> tuple[AxisRange, AxisRange]:
+    if not spaces:
+        return default.pan, default.tilt
+    space = spaces[0]
+    return (
+        AxisRange(float(space.XRange.Min), float(space.XRange.Max)),
+        AxisRange(float(space.YRange.Min), float(space.YRange.Max)),
+    )
+
+
+def _space_1d(spaces, default: AxisRange) -> AxisRange:
+    if not spaces:
+        return default
+    return AxisRange(float(spaces[0].XRange.Min), float(spaces[0].XRange.Max))
+
+
+def get_bounds(ptz_service, configuration_token: str) -> PtzBounds:
+    """Read the absolute position and relative translation spaces of a PTZ configuration."""
+    options = call(ptz_service, "GetConfigurationOptions", {"ConfigurationToken": configuration_token})
+
+    spaces = options.Spaces
+    absolute, relative = _default_absolute_space(), _default_relative_space()
+    if spaces is None:
+        return PtzBounds(absolute, relative)
+
+    # The first space of each kind is the generic one cameras use when a request doesn't name a space.
+    absolute.pan, absolute.tilt = _space_2d(spaces.AbsolutePanTiltPositionSpace, absolute)
+    absolute.zoom = _space_1d(spaces.AbsoluteZoomPositionSpace, absolute.zoom)
+    relative.pan, relative.tilt = _space_2d(spaces.RelativePanTiltTranslationSpace, relative)
+    relative.zoom = _space_1d(spaces.RelativeZoomTranslationSpace, relative.zoom)
+    return PtzBounds(absolute, relative)
+
+
+def _axes(command: dict, default: Optional[float]) -> tuple[Optional[float], Optional[float], Optional[float]]:
+    pan, tilt, zoom = (
+        float(command[axis]) if command.get(axis) is not None else default for axis in ("pan", "tilt", "zoom")
+    )
+    if (pan is None) != (tilt is None):
+        raise ValueError("pan and tilt must be given together")
+    if pan is None and zoom is None:
+        raise ValueError("no pan, tilt or zoom given")
+    return pan, tilt, zoom
+
+
+Velocity = tuple[float, float, float]
+
+
+class MoveCoalescer:
+    """
+    Sends at most one continuous move per `interval`, always the latest velocity; the ones in between are dropped.
+    A joystick can produce dozens of commands per second, more than a camera's HTTP endpoint keeps up with.
+    Stops bypass the coalescing and discard the pending move, so the camera stops promptly.
+    """
+
+    def __init__(
+        self, interval: float, send_move: Callable[[float, float, float], None], send_stop: Callable[[], None]
+    ):
+        self.interval = interval
+        self.send_move = send_move
+        self.send_stop = send_stop
+        # Held while sending, so a stop can't overtake a move that is already on its way.
+        self._lock = threading.Lock()
+        self._pending: Optional[Velocity] = None
+        self._timer: Optional[threading.Timer] = None
+        self._last_sent = float("-inf")
+
+    def move(self, pan: float, tilt: float, zoom: float):
+        with self._lock:
+            wait = self._last_sent + self.interval - time.monotonic()
+            if wait <= 0 and self._timer is None:
+                self._send((pan, tilt, zoom))
+                return
+            self._pending = (pan, tilt, zoom)
+            if self._timer is None:
+                self._timer = threading.Timer(max(wait, 0.0), self._flush)
+                self._timer.daemon = True
+                self._timer.start()
+
+    def stop(self):
+        with self._lock:
+            self._discard_pending()
+            self.send_stop()
+
+    def cancel(self):
+        """Drop the pending move, e.g. when another kind of move replaces it."""
+        with self._lock:
+            self._discard_pending()
+
+    def _flush(self):
+        with self._lock:
+            self._timer = None
+            velocity, self._pending = self._pending, None
+            if velocity is not None:
+                self._send(velocity)
+
+    def _send(self, velocity: Velocity):
+        self._last_sent = time.monotonic()
+        self.send_move(*velocity)
+
+    def _discard_pending(self):
+        self._pending = None
+        if self._timer is not None:
+            self._timer.cancel()
+            self._timer = None
+
+
+class PtzController:
+    """
+    Translates commands received on a topic into PTZ moves:
+    `{"pan", "tilt", "zoom"}` velocities (ContinuousMove), or positions with `"action": "absolute"` / `"relative"`.
+    Presets are addressed by name: `{"action": "goto" | "set" | "remove", "preset": "front_door"}`.
+    After a continuous move the camera is stopped when no new command arrives within `command_timeout` seconds,
+    so a crashed controller cannot leave it panning forever. Velocities are sent at most every `command_interval`
+    seconds (see `MoveCoalescer`).
+    """
+
+    def __init__(self, camera_id: str, command_timeout: float, command_interval: float = 0.1):
+        self.camera_id = camera_id
+        self.command_timeout = command_timeout
+        self.ptz_service = None
+        self.profile_token = None
+        self.bounds = PtzBounds()
+        # Never call into the coalescer while holding `_lock`: it calls back into `_send_move`/`_send_stop`.
+        self._moves = MoveCoalescer(command_interval, self._send_move, self._send_stop)
+        self._lock = threading.Lock()
+        self._dead_man_timer = None
+        self._command_count = 0
+        # Well-formed commands addressed to this camera, unlike `_command_count` including presets and rejected moves.
+        self._commands_processed = 0
+
+    def attach(self, camera: ONVIFCamera, profile_token: str, configuration_token: Optional[str] = None):
+        """Direct commands to `camera`, e.g. after (re)connecting."""
+        with onvif_errors("create PTZ service"):
+            ptz_service = create_service(camera, "ptz")
+
+        bounds = PtzBounds()
+        if configuration_token is not None:
+            try:
+                bounds = get_bounds(ptz_service, configuration_token)
+            except SoapError as e:
+                logger.warning(f"Could not read the PTZ coordinate spaces ({e}), assuming the generic spaces.")
+
+        try:
+            presets = get_presets(ptz_service, profile_token)
+            logger.info(f"PTZ presets: {', '.join(preset.name for preset in presets) or 'none'}")
+        except SoapError as e:
+            logger.info(f"Could not list PTZ presets: {e}")
+
+        with self._lock:
+            self.ptz_service = ptz_service
+            self.profile_token = profile_token
+            self.bounds = bounds
+
+    @property
+    def commands_processed(self) -> int:
+        with self._lock:
+            return self._commands_processed
+
+    def detach(self):
+        self._moves.cancel()
+        with self._lock:
+            self._cancel_dead_man_timer()
+            self.ptz_service = None
+            self.profile_token = None
+
+    def handle_command(self, message: PlainText):
+        # Commands arrive on make87 subscriber threads, outside of the camera's task.
+        with camera_context(self.camera_id):
+            self._handle_command(message)
+
+    def _handle_command(self, message: PlainText):
+        try:
+            command = json.loads(message.body)
+            # Commands without a "camera" field address every camera.
+            if command.get("camera", self.camera_id) != self.camera_id:
+                return
+            action = command.get("action", "continuous")
+            if action in PRESET_ACTIONS:
+                preset_name = command.get("preset")
+                if not isinstance(preset_name, str) or not preset_name:
+                    raise ValueError("preset must be a non-empty string")
+                speed = float(command["speed"]) if command.get("speed") is not None else None
+            elif action in MOVE_ACTIONS:
+                # Velocities default to 0; positions only move the axes that are given.
+                pan, tilt, zoom = _axes(command, default=0.0 if action == "continuous" else None)
+            else:
+                raise ValueError(f"unknown action {action!r}")
+        except (ValueError, TypeError, AttributeError) as e:
+            logger.warning(f"Ignoring malformed PTZ command {message.body!r}: {e}")
+            return
+
+        with self._lock:
+            self._commands_processed += 1
+        if action in PRESET_ACTIONS:
+            self._handle_preset_command(action, preset_name, speed)
+            return
+        if action == "continuous":
+            self._handle_continuous_command(pan, tilt, zoom)
+            return
+
+        with self._lock:
+            connected, bounds = self.ptz_service is not None, self.bounds
+        if not connected:
+            logger.warning("No PTZ capable camera connected, dropping PTZ command.")
+            return
+        try:
+            if action == "absolute":
+                bounds.absolute.check(pan, tilt, zoom)
+            elif action == "relative":
+                bounds.relative.check(pan, tilt, zoom)
+        except ValueError as e:
+            logger.warning(f"Rejecting PTZ {action} move: {e}")
+            return
+
+        # A position move replaces any continuous move that is still waiting to be sent.
+        self._moves.cancel()
+        with self._lock:
+            if self.ptz_service is None:
+                return
+            self._cancel_dead_man_timer()
+            self._command_count += 1
+            try:
+                if action == "absolute":
+                    absolute_move(self.ptz_service, self.profile_token, pan, tilt, zoom)
+                else:
+                    relative_move(self.ptz_service, self.profile_token, pan, tilt, zoom)
+            except OnvifError as e:
+                logger.error(f"PTZ command failed: {e}")
+
+    def _handle_continuous_command(self, pan: float, tilt: float, zoom: float):
+        with self._lock:
+            if self.ptz_service is None:
+                logger.warning("No PTZ capable camera connected, dropping PTZ command.")
+                return
+            self._cancel_dead_man_timer()
+            self._command_count += 1
+            is_stop = pan == 0.0 and tilt == 0.0 and zoom == 0.0
+            if not is_stop:
+                # Every command keeps the move alive, including those the coalescer drops.
+                self._dead_man_timer = threading.Timer(
+                    self.command_timeout, self._on_command_timeout, args=(self._command_count,)
+                )
+                self._dead_man_timer.daemon = True
+                self._dead_man_timer.start()
+
+        if is_stop:
+            self._moves.stop()
+        else:
+            self._moves.move(pan, tilt, zoom)
+
+    def _send_move(self, pan: float, tilt: float, zoom: float):
+        # Runs on the subscriber thread or, for coalesced moves, on the coalescer's timer thread.
+        with camera_context(self.camera_id):
+            with self._lock:
+                ptz_service, profile_token = self.ptz_service, self.profile_token
+            if ptz_service is None:
+                return
+            try:
+                continuous_move(ptz_service, profile_token, pan, tilt, zoom)
+            except OnvifError as e:
+                logger.error(f"PTZ command failed: {e}")
+
+    def _send_stop(self):
+        with self._lock:
+            ptz_service, profile_token = self.ptz_service, self.profile_token
+        if ptz_service is None:
+            return
+        try:
+            stop(ptz_service, profile_token)
+        except OnvifError as e:
+            logger.error(f"PTZ stop failed: {e}")
+
+    def _handle_preset_command(self, action: str, preset_name: str, speed: Optional[float]):
+        if action == "goto":
+            self._moves.cancel()
+        with self._lock:
+            if self.ptz_service is None:
+                logger.warning("No PTZ capable camera connected, dropping PTZ preset command.")
+                return
+
+            try:
+                preset = find_preset(get_presets(self.ptz_service, self.profile_token), preset_name)
+                if action == "set":
+                    token = set_preset(
+                        self.ptz_service, self.profile_token, preset_name, preset.token if preset else None
+                    )
+                    logger.info(f"Stored PTZ preset {preset_name!r} (token {token}).")
+                    return
+
+                if preset is None:
+                    logger.warning(f"PTZ preset {preset_name!r} does not exist, ignoring {action} command.")
+                    return
+                if action == "remove":
+                    remove_preset(self.ptz_service, self.profile_token, preset.token)
+                    logger.info(f"Removed PTZ preset {preset_name!r}.")
+                    return
+
+                # Like absolute moves, going to a preset replaces any running continuous move.
+                self._cancel_dead_man_timer()
+                self._command_count += 1
+                goto_preset(self.ptz_service, self.profile_token, preset.token, speed)
+            except OnvifError as e:
+                logger.error(f"PTZ preset command failed: {e}")
+
+    def status(self) -> Optional[PtzStatus]:
+        """The current position, or `None` while no camera is attached."""
+        with self._lock:
+            ptz_service, profile_token = self.ptz_service, self.profile_token
+        if ptz_service is None:
+            return None
+        return get_status(ptz_service, profile_token)
+
+    def _cancel_dead_man_timer(self):
+        if self._dead_man_timer is not None:
+            self._dead_man_timer.cancel()
+            self._dead_man_timer = None
+
+    def _on_command_timeout(self, command_count: int):
+        with camera_context(self.camera_id):
+            with self._lock:
+                if command_count != self._command_count or self.ptz_service is None:
+                    return  # A newer command re-armed the timer while this one was firing.
+                self._dead_man_timer = None
+            logger.warning(f"No PTZ command received for {self.command_timeout}s, stopping camera.")
+            self._moves.stop()
+
+
+async def poll_ptz_status(controller: PtzController, topic, interval: float, entity_path: str):
+    """Publish the PTZ position every `interval` seconds until cancelled."""
+    while True:
+        await asyncio.sleep(interval)
+        try:
+            status = await asyncio.to_thread(controller.status)
+        except OnvifError as e:
+            logger.warning(f"PTZ GetStatus failed: {e}")
+            continue
+        if status is None:
+            continue
+
+        header = Header(entity_path=entity_path)
+        header.timestamp.FromDatetime(datetime.now())
+        await asyncio.to_thread(topic.publish, PlainText(header=header, body=status.to_json()))
@@ -0,0 +1,51 @@
+import asyncio
+import logging
+import random
+from dataclasses import dataclass
+from typing import Awaitable, Callable, Optional, TypeVar
+
+from app.error import OnvifError
+
+logger = logging.getLogger(__name__)
+
+T = TypeVar("T")
+
+
+@dataclass
+class BackoffPolicy:
+    base_delay: float = 1.0
+    max_delay: float = 30.0
+    # 0 retries forever.
+    max_attempts: int = 0
+    # Fraction of the delay that is randomized, so several drivers don't reconnect in lockstep.
+    jitter: float = 0.2
+
+    def delay(self, attempt: int) -> float:
+        delay = min(self.max_delay, self.base_delay * 2 ** (attempt - 1))
+        return delay * (1.0 - self.jitter * random.random())
+
+
+async def with_backoff(
+    op: Callable[[], Awaitable[T]],
+    policy: BackoffPolicy,
+    on_retry: Optional[Callable[[OnvifError], None]] = None,
+) -> T:
+    """
+    Call `op` until it succeeds, sleeping with exponential backoff between retryable failures.
+    Non-retryable errors (e.g. bad credentials) and exhausting `max_attempts` re-raise the last error.
+    Every call starts from `base_delay` again, i.e. the backoff resets once an operation succeeded.
+    """
+    attempt = 0
+    while True:
+        try:
+            return await op()
+        except OnvifError as e:
+            attempt += 1
+            if not e.retryable or (policy.max_attempts and attempt >= policy.max_attempts):
+                raise
+
+            delay = policy.delay(attempt)
+            logger.warning(f"{e}; retrying in {delay:.1f}s (attempt {attempt})")
+            if on_retry is not None:
+                on_retry(e)
+            await asyncio.sleep(delay)
@@ -0,0 +1,556 @@
+import asyncio
+import base64
+import contextlib
+import itertools
+import json
+import logging
+import threading
+import time
+from dataclasses import dataclass
+from datetime import datetime, timedelta, timezone
+from typing import Callable, Iterator, Optional
+from urllib.parse import urlparse, urlunparse
+
+import av
+from make87_messages.core.header_pb2 import Header
+from make87_messages.text.text_plain_pb2 import PlainText
+from make87_messages.video.any_pb2 import FrameAny
+from make87_messages.video.frame_av1_pb2 import FrameAV1
+from make87_messages.video.frame_h264_pb2 import FrameH264
+from make87_messages.video.frame_h265_pb2 import FrameH265
+
+from app.error import NetworkError, OnvifError, StreamStalled, onvif_errors
+from app.frame_queue import FramePublisher, FrameQueue, FrameRateLimiter
+from app.stream_info import VideoStreamInfo, stream_info_from_parameter_sets
+
+logger = logging.getLogger(__name__)
+
+ANNEX_B_START_CODE = b"\x00\x00\x01"
+METRICS_INTERVAL = 1.0
+RTSP_TRANSPORTS = ("udp", "tcp", "auto")
+# Seconds to wait for the RTSP DESCRIBE/SETUP/PLAY handshake.
+OPEN_TIMEOUT = 10.0
+# Seconds between stall checks, and to wait for a stopped stream thread before giving up on it.
+WATCHDOG_INTERVAL = 1.0
+STOP_TIMEOUT = 5.0
+# FFmpeg depacketizes these RTP payloads (RFC 3551 PCMU/PCMA, RFC 3640 AAC) into raw audio frames.
+AUDIO_CODECS = {"pcm_mulaw": "PCMU", "pcm_alaw": "PCMA", "aac": "AAC"}
+
+# NAL unit types carrying parameter sets, per codec.
+PARAMETER_SET_NAL_TYPES = {
+    "h264": {7, 8},  # SPS, PPS
+    "hevc": {32, 33, 34},  # VPS, SPS, PPS
+}
+
+
+@dataclass
+class RtspSettings:
+    # "udp", "tcp" (RTP interleaved in the RTSP connection) or "auto": UDP, falling back to TCP without media.
+    transport: str = "auto"
+    # Seconds without a packet before the stream counts as broken, and in "auto" mode UDP as blocked.
+    media_timeout: float = 5.0
+    # Frames buffered for a slow publisher, and what happens once the buffer is full (see `FrameQueue`).
+    queue_capacity: int = 60
+    overflow_policy: str = "drop_oldest"
+    # Seconds without a video frame after which a stream that raised no error is torn down and set up again.
+    stall_timeout: float = 15.0
+    # Publish about this many video frames per second (see `FrameRateLimiter`); `None` publishes all of them.
+    publish_fps: Optional[float] = None
+
+
+@dataclass
+class OpenedStream:
+    """An RTSP session whose first packet has arrived. Closing it sends the RTSP TEARDOWN."""
+
+    container: "av.container.InputContainer"
+    stream: "av.video.stream.VideoStream"
+    # Video and, if requested and supported, audio packets in arrival order.
+    packets: Iterator["av.Packet"]
+    audio: Optional["av.audio.stream.AudioStream"] = None
+    # The RTP transport media arrived over, "udp" or "tcp".
+    transport: str = "udp"
+
+    def timestamp(self, packet: "av.Packet") -> float:
+        """
+        Seconds since the start of the session. FFmpeg maps all tracks onto one timeline using the RTCP sender
+        reports, so audio and video timestamps are measured from the same origin.
+        """
+        if self.container.start_time is not None:
+            origin = self.container.start_time / av.time_base
+        else:
+            origin = (self.stream.start_time or 0) * float(self.stream.time_base)
+        return packet.pts * float(packet.time_base) - origin
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *exc_info):
+        self.container.close()
+
+
+@dataclass
+class StreamMetrics:
+    # Measured over the last metrics interval, from the packets actually received.
+    fps: float
+    bitrate: float  # bytes per second
+    # Frames and seconds between the last two keyframes; `None` until two keyframes were seen.
+    gop_size: Optional[int]
+    keyframe_interval: Optional[float]
+    # Video frames dropped since the stream started because publishing fell behind.
+    dropped_frames: int = 0
+    # Video frames actually published per second, below `fps` when the publish rate is capped or frames are dropped.
+    published_fps: float = 0.0
+
+
+class StallWatchdog:
+    """
+    Notices a stream that stopped delivering frames without failing, e.g. a socket that only carries RTCP.
+    It arms with the first frame: until then the RTSP handshake and first-packet timeouts apply.
+    """
+
+    def __init__(self, timeout: float):
+        self.timeout = timeout
+        self._last_frame_at: Optional[float] = None
+
+    def frame(self):
+        self._last_frame_at = time.monotonic()
+
+    def stalled(self) -> bool:
+        last_frame_at = self._last_frame_at
+        return last_frame_at is not None and time.monotonic() - last_frame_at > self.timeout
+
+
+class MetricsCollector:
+    """Accumulates received packets into rolling FPS, bitrate and GOP measurements."""
+
+    def __init__(self, interval: float = METRICS_INTERVAL):
+        self.interval = interval
+        self._window_start = time.monotonic()
+        self._frames = 0
+        self._bytes = 0
+        self._frames_since_keyframe = None
+        self._last_keyframe_timestamp = None
+        self._gop_size = None
+        self._keyframe_interval = None
+        self._published_at_window_start = 0
+
+    def record(self, size: int, is_keyframe: bool, timestamp: float):
+        """Count one packet; `timestamp` is its presentation time in seconds."""
+        self._frames += 1
+        self._bytes += size
+        if is_keyframe:
+            if self._frames_since_keyframe is not None:
+                self._gop_size = self._frames_since_keyframe
+                self._keyframe_interval = timestamp - self._last_keyframe_timestamp
+            self._frames_since_keyframe = 0
+            self._last_keyframe_timestamp = timestamp
+        if self._frames_since_keyframe is not None:
+            self._frames_since_keyframe += 1
+
+    def take(self, published: int = 0) -> Optional[StreamMetrics]:
+        """
+        The metrics of the elapsed window once it is `interval` long, starting a new window.
+        `published` is the number of frames published since the stream started.
+        """
+        now = time.monotonic()
+        elapsed = now - self._window_start
+        if elapsed < self.interval:
+            return None
+
+        metrics = StreamMetrics(
+            fps=self._frames / elapsed,
+            bitrate=self._bytes / elapsed,
+            gop_size=self._gop_size,
+            keyframe_interval=self._keyframe_interval,
+            published_fps=(published - self._published_at_window_start) / elapsed,
+        )
+        self._window_start = now
+        self._frames = 0
+        self._bytes = 0
+        self._published_at_window_start = published
+        return metrics
+
+
+def inject_rtsp_auth(uri: str, username: str, password: str) -> str:
+    parsed = urlparse(uri)
+
+    netloc_with_auth = f"{username}:{password}@{parsed.hostname}"
+    if parsed.port:
+        netloc_with_auth += f":{parsed.port}"
+
+    return urlunparse(
+        (
+            parsed.scheme,
+            netloc_with_auth,
+            parsed.path,
+            parsed.params,
+            parsed.query,
+            parsed.fragment,
+        )
+    )
+
+
+def nal_units(data: bytes) -> Iterator[bytes]:
+    """Split an Annex B byte stream into NAL units (without start codes)."""
+    start = data.find(ANNEX_B_START_CODE)
+    while start != -1:
+        start += len(ANNEX_B_START_CODE)
+        end = data.find(ANNEX_B_START_CODE, start)
+        nal = data[start:] if end == -1 else data[start:end]
+        # A four byte start code leaves a trailing zero on the previous unit.
+        yield nal.rstrip(b"\x00") if end != -1 else nal
+        start = end
+
+
+def nal_unit_type(codec: str, nal: bytes) -> Optional[int]:
+    if not nal:
+        return None
+    if codec == "h264":
+        return nal[0] & 0x1F
+    if codec == "hevc":
+        return (nal[0] >> 1) & 0x3F
+    return None
+
+
+def has_parameter_sets(codec: str, data: bytes) -> bool:
+    types = PARAMETER_SET_NAL_TYPES.get(codec, set())
+    return any(nal_unit_type(codec, nal) in types for nal in nal_units(data))
+
+
+def parameter_set_units(codec: str, data: bytes) -> dict[int, bytes]:
+    """The parameter set NAL units in an Annex B byte stream, keyed by NAL unit type."""
+    types = PARAMETER_SET_NAL_TYPES.get(codec, set())
+    return {nal_type: nal for nal in nal_units(data) if (nal_type := nal_unit_type(codec, nal)) in types}
+
+
+def is_disposable(codec: str, data: bytes) -> bool:
+    """Whether no other frame references this one, so dropping it leaves the rest of the stream decodable."""
+    slices = []
+    for nal in nal_units(data):
+        nal_type = nal_unit_type(codec, nal)
+        if codec == "h264" and nal_type in range(1, 6):
+            slices.append(nal)
+        elif codec == "hevc" and nal_type is not None and nal_type < 32:
+            slices.append(nal)
+    if not slices:
+        return False
+    if codec == "h264":
+        # nal_ref_idc 0 marks a picture that is never used for reference.
+        return all(nal[0] & 0x60 == 0 for nal in slices)
+    # Even VCL types up to RSV_VCL_N14 (TRAIL_N, TSA_N, STSA_N, RADL_N, RASL_N) are sub-layer non-reference pictures.
+    return all(nal_unit_type(codec, nal) <= 14 and nal_unit_type(codec, nal) % 2 == 0 for nal in slices)
+
+
+def annex_b(units: dict[int, bytes]) -> bytes:
+    # Parameter set types are numbered in the order decoders expect them (VPS, SPS, PPS).
+    return b"".join(b"\x00\x00\x00\x01" + units[nal_type] for nal_type in sorted(units))
+
+
+def annex_b_parameter_sets(codec: str, extradata: Optional[bytes]) -> Optional[bytes]:
+    """
+    Return the parameter sets FFmpeg parsed from the SDP (sprop-parameter-sets), if they are in Annex B form.
+    """
+    if codec not in PARAMETER_SET_NAL_TYPES or not extradata:
+        return None
+    extradata = bytes(extradata)
+    if not has_parameter_sets(codec, extradata):
+        return None
+    return extradata
+
+
+# Generic function for encoding frames
+def encode_frame(
+    codec, header, packet: av.Packet, width: int, height: int, data: Optional[bytes] = None
+) -> FrameAny:
+    codec_classes = {
+        "h264": ("h264", FrameH264),
+        "hevc": ("h265", FrameH265),
+        "av1": ("av1", FrameAV1),
+    }
+
+    if codec not in codec_classes:
+        raise ValueError(f"Unsupported codec: {codec}")
+
+    codec_field, codec_class = codec_classes[codec]
+    sub_message = codec_class(
+        header=header,
+        data=bytes(packet) if data is None else data,
+        width=width,
+        height=height,
+        is_keyframe=packet.is_keyframe,
+        pts=packet.pts,
+        dts=packet.dts,
+        duration=packet.duration,
+        time_base=codec_class.Fraction(
+            num=packet.time_base.numerator,
+            den=packet.time_base.denominator,
+        ),
+    )
+
+    return FrameAny(header=header, **{codec_field: sub_message})
+
+
+def encode_audio_frame(header, packet: av.Packet, audio_stream, timestamp: float) -> PlainText:
+    codec_context = audio_stream.codec_context
+    frame = {
+        "codec": AUDIO_CODECS[codec_context.name],
+        "sample_rate": codec_context.sample_rate,
+        "channels": codec_context.channels,
+        "timestamp": timestamp,
+        "pts": packet.pts,
+        "time_base": f"{packet.time_base.numerator}/{packet.time_base.denominator}",
+        "data": base64.b64encode(bytes(packet)).decode("ascii"),
+    }
+    if codec_context.name == "aac" and codec_context.extradata:
+        # Raw AAC access units need the AudioSpecificConfig from the SDP to be decoded.
+        frame["config"] = base64.b64encode(bytes(codec_context.extradata)).decode("ascii")
+    return PlainText(header=header, body=json.dumps(frame))
+
+
+def _audio_stream(container: "av.container.InputContainer"):
+    for audio_stream in container.streams.audio:
+        if audio_stream.codec_context.name in AUDIO_CODECS:
+            return audio_stream
+        logger.info(f"Unsupported audio codec {audio_stream.codec_context.name}, not publishing it.")
+    return None
+
+
+def check_annex_b_format(packet: av.Packet):
+    """
+    Check if the packet is in Annex B format.
+    This is typically used for H.264 streams.
+    """
+    # Check if the packet starts with the Annex B start code
+    data = bytes(packet)  # get the raw packet bytes
+    if not (data.startswith(b"\x00\x00\x00\x01") or data.startswith(b"\x00\x00\x01")):
+        raise NotImplementedError("Only Annex B format is supported for H.264/H.265 streams.")
+
+
+def _open_with_transport(uri: str, transport: str, media_timeout: float, with_audio: bool) -> OpenedStream:
+    container = av.open(uri, options={"rtsp_transport": transport}, timeout=(OPEN_TIMEOUT, media_timeout))
+    try:
+        video_streams = container.streams.video
+        if len(video_streams) == 0:
+            raise ValueError("No video stream not found.")
+        video_stream = video_streams[0]
+        audio_stream = _audio_stream(container) if with_audio else None
+
+        # A successful PLAY doesn't mean media arrives; only a received packet proves the transport works.
+        packets = container.demux(video_stream, audio_stream) if audio_stream else container.demux(video_stream)
+        first_packet = next((packet for packet in packets if packet.size), None)
+        if first_packet is None:
+            raise NetworkError(f"RTSP stream over {transport.upper()} ended before any packet was received.")
+    except BaseException:
+        container.close()
+        raise
+    return OpenedStream(container, video_stream, itertools.chain([first_packet], packets), audio_stream, transport)
+
+
+def open_stream(uri: str, settings: RtspSettings, with_audio: bool = False) -> OpenedStream:
+    """
+    Open the RTSP stream with the configured transport and wait for the first packet.
+    In "auto" mode, a UDP session that delivers no media within `media_timeout` (e.g. behind NAT)
+    is set up again with RTP-over-TCP interleaved.
+    """
+    if settings.transport != "auto":
+        return _open_with_transport(uri, settings.transport, settings.media_timeout, with_audio)
+    try:
+        return _open_with_transport(uri, "udp", settings.media_timeout, with_audio)
+    except (av.error.FFmpegError, NetworkError) as e:
+        logger.warning(f"No media received over UDP ({e}), switching to TCP interleaved transport.")
+        return _open_with_transport(uri, "tcp", settings.media_timeout, with_audio)
+
+
+def stream_video(
+    topic,
+    stream_uri: str,
+    entity_path: str,
+    on_streaming: Callable[[], None],
+    on_frame: Optional[Callable[[int], None]] = None,
+    on_metrics: Optional[Callable[[StreamMetrics], None]] = None,
+    stop: Optional[threading.Event] = None,
+    settings: Optional[RtspSettings] = None,
+    audio_topic=None,
+    on_stream_info: Optional[Callable[[VideoStreamInfo], None]] = None,
+    watchdog: Optional[StallWatchdog] = None,
+):
+    """
+    Publish the RTSP stream until it ends or `stop` is set, and its audio track on `audio_topic` if it has one.
+    `on_stream_info` gets the codec parameters once they are known, and again whenever the in-band ones change.
+    Failures before the first packet are raised, so they are retried with backoff;
+    once frames were flowing, a broken stream just ends the session.
+    """
+    streaming = False
+    settings = settings or RtspSettings()
+    try:
+        with onvif_errors("RTSP stream"), contextlib.ExitStack() as resources:
+            video = resources.enter_context(open_stream(stream_uri, settings, with_audio=audio_topic is not None))
+            # Header timestamps are estimates: our clock at the first packet plus the media time since then.
+            # FFmpeg reads the RTCP sender reports itself to align the tracks, but PyAV doesn't expose their
+            # NTP time, so the camera's wall clock can't be recovered here.
+            stream_start = datetime.now(timezone.utc)
+            video_stream = video.stream
+
+            # Print stream information
+            stream_info = {
+                "Index": video_stream.index,
+                "Codec": video_stream.codec_context.name,
+                "Resolution": f"{video_stream.width}x{video_stream.height}",
+                "Pixel Format": video_stream.pix_fmt,
+                "Frame Rate": str(video_stream.average_rate),
+            }
+            logger.info(f"Stream Attributes: {stream_info}")
+            if video.audio is not None:
+                audio_context = video.audio.codec_context
+                logger.info(
+                    f"Audio track: {AUDIO_CODECS[audio_context.name]} "
+                    f"{audio_context.sample_rate} Hz, {audio_context.channels} channel(s)"
+                )
+
+            # Validate codec support
+            codec_name = video_stream.codec_context.name
+            if codec_name not in {"h264", "hevc", "av1"}:
+                raise ValueError(f"Unsupported codec: {codec_name}")
+
+            # Stream metadata
+            width, height = video_stream.width, video_stream.height
+
+            # Keyframes without in-band SPS/PPS get the latest known ones (from the SDP or an earlier keyframe)
+            # prepended, so decoders can join mid-stream.
+            parameter_sets = annex_b_parameter_sets(codec_name, video_stream.codec_context.extradata)
+            announced_info = None
+            if parameter_sets:
+                units = parameter_set_units(codec_name, parameter_sets)
+                announced_info = stream_info_from_parameter_sets(codec_name, units, width, height)
+                if announced_info is not None and on_stream_info is not None:
+                    on_stream_info(announced_info)
+
+            validated_annex_b = False
+            metrics = MetricsCollector()
+            limiter = FrameRateLimiter(settings.publish_fps) if settings.publish_fps else None
+
+            # Frames are handed to publisher threads through bounded queues, so a slow consumer costs frames
+            # instead of memory. The publishers are stopped before the container is closed.
+            video_queue = FrameQueue(settings.queue_capacity, settings.overflow_policy)
+            video_publisher = resources.enter_context(FramePublisher(topic, video_queue, on_frame, stop))
+            if video.audio is not None:
+                audio_queue = FrameQueue(settings.queue_capacity, settings.overflow_policy)
+                audio_publisher = resources.enter_context(FramePublisher(audio_topic, audio_queue, stop=stop))
+
+            for packet in video.packets:
+                if stop is not None and stop.is_set():
+                    # Leaving the `with` block closes the container, which sends the RTSP TEARDOWN.
+                    return
+                if packet.dts is None:
+                    continue  # Skip invalid frames
+
+                if video.audio is not None and packet.stream.index == video.audio.index:
+                    audio_timestamp = video.timestamp(packet)
+                    header = Header(entity_path=entity_path)
+                    header.timestamp.FromDatetime(stream_start + timedelta(seconds=audio_timestamp))
+                    # Every audio frame decodes on its own.
+                    audio_publisher.put(encode_audio_frame(header, packet, video.audio, audio_timestamp), True)
+                    continue
+
+                if not validated_annex_b:
+                    if codec_name in {"h264", "hevc"}:
+                        # Check for Annex B format
+                        check_annex_b_format(packet)
+                    validated_annex_b = True
+
+                if not streaming:
+                    streaming = True
+                    on_streaming()
+                if watchdog is not None:
+                    watchdog.frame()
+
+                # Compute timestamps
+                relative_timestamp = video.timestamp(packet)
+                header = Header(entity_path=entity_path)
+                header.timestamp.FromDatetime(stream_start + timedelta(seconds=relative_timestamp))
+
+                data = bytes(packet)
+                in_band_units = parameter_set_units(codec_name, data) if packet.is_keyframe else {}
+                if packet.is_keyframe and parameter_sets and not in_band_units:
+                    data = parameter_sets + data
+                elif in_band_units:
+                    # Some cameras announce different parameter sets in the SDP than they actually encode with.
+                    info = stream_info_from_parameter_sets(codec_name, in_band_units, width, height)
+                    if info is not None and info != announced_info:
+                        if announced_info is not None:
+                            logger.warning(
+                                f"In-band parameter sets differ from the announced ones "
+                                f"({announced_info.width}x{announced_info.height} -> {info.width}x{info.height}), "
+                                "re-publishing the stream info."
+                            )
+                        announced_info = info
+                        parameter_sets = annex_b(in_band_units)
+                        if on_stream_info is not None:
+                            on_stream_info(info)
+
+                # Encode and queue the frame for publishing, unless it falls to the publish rate cap
+                disposable = limiter is not None and not packet.is_keyframe and is_disposable(codec_name, data)
+                if limiter is None or limiter.allow(relative_timestamp, packet.is_keyframe, disposable):
+                    frame = encode_frame(codec_name, header, packet, width, height, data=data)
+                    video_publisher.put(frame, packet.is_keyframe, size=len(data))
+
+                metrics.record(len(data), packet.is_keyframe, relative_timestamp)
+                stream_metrics = metrics.take(published=video_publisher.published)
+                if stream_metrics is not None and on_metrics is not None:
+                    stream_metrics.dropped_frames = video_queue.dropped
+                    on_metrics(stream_metrics)
+
+        if not streaming:
+            raise NetworkError("RTSP stream ended before any frame was received.")
+    except OnvifError as e:
+        if not streaming:
+            raise
+        logger.warning(f"Stream interrupted: {e}")
+
+
+async def _stop_stream(stream: asyncio.Future, stop: threading.Event):
+    stop.set()
+    try:
+        await asyncio.wait_for(asyncio.shield(stream), STOP_TIMEOUT)
+    except asyncio.TimeoutError:
+        # The thread is stuck in a read; it exits (and sends the TEARDOWN) once FFmpeg's read timeout hits.
+        logger.warning(f"Stream thread did not stop within {STOP_TIMEOUT}s, abandoning it.")
+    except Exception:
+        pass
+
+
+async def run_stream(
+    topic,
+    stream_uri: str,
+    entity_path: str,
+    settings: Optional[RtspSettings] = None,
+    on_stall: Optional[Callable[[], None]] = None,
+    **callbacks,
+):
+    """
+    Run `stream_video` in a worker thread, since PyAV demuxing blocks.
+    Cancelling stops the thread and waits for it, so the camera gets a TEARDOWN and frees the session slot.
+    A stream that delivers no frame for `stall_timeout` is stopped the same way and raises `StreamStalled`,
+    so it is set up again like any other broken stream.
+    """
+    settings = settings or RtspSettings()
+    stop = threading.Event()
+    watchdog = StallWatchdog(settings.stall_timeout)
+    stream = asyncio.ensure_future(
+        asyncio.to_thread(
+            stream_video, topic, stream_uri, entity_path, stop=stop, settings=settings, watchdog=watchdog, **callbacks
+        )
+    )
+    try:
+        while not stream.done():
+            await asyncio.wait([stream], timeout=WATCHDOG_INTERVAL)
+            if not stream.done() and watchdog.stalled():
+                logger.warning(f"No video frame for {settings.stall_timeout:g}s, restarting the stream.")
+                if on_stall is not None:
+                    on_stall()
+                await _stop_stream(stream, stop)
+                raise StreamStalled(f"RTSP stream delivered no frame for {settings.stall_timeout:g}s.")
+        return stream.result()
+    except asyncio.CancelledError:
+        await _stop_stream(stream, stop)
+        raise
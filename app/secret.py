@@ -0,0 +1,96 @@
+import logging
+import os
+import re
+import threading
+from pathlib import Path
+from urllib.parse import quote
+
+logger = logging.getLogger(__name__)
+
+REDACTED = "***"
+# Where the platform mounts secrets, one file per secret.
+SECRETS_DIR = Path("/run/secrets")
+SECRET_PREFIX = "secret:"
+ENV_PREFIX = "env:"
+
+# Shorter secrets are only scrubbed in credential positions: replacing "admin" or "1234" anywhere mangles logs.
+MIN_REDACTED_LENGTH = 6
+# `scheme://user:password@` in URLs, and `<wsse:Password ...>password</wsse:Password>` in SOAP dumps.
+USERINFO = re.compile(r"(?P<prefix>\b[a-z][a-z0-9+.-]*://[^:@/\s]*:)[^@/\s]*@", re.IGNORECASE)
+PASSWORD_ELEMENT = re.compile(r"(?P<open><(?:[\w.-]+:)?Password\b[^>]*>)[^<]*(?=</)")
+
+# Every secret value the driver holds, including the forms it takes in URLs, so `redact` can scrub them.
+_known_values: set[str] = set()
+_lock = threading.Lock()
+
+
+class Secret:
+    """A sensitive string that prints as `***`. Only `reveal()` hands out the value."""
+
+    __slots__ = ("_value",)
+
+    def __init__(self, value: str):
+        self._value = value
+        if value:
+            with _lock:
+                _known_values.update({value, quote(value, safe=""), quote(value)})
+
+    def reveal(self) -> str:
+        return self._value
+
+    def __repr__(self) -> str:
+        return REDACTED
+
+    __str__ = __repr__
+
+    def __eq__(self, other) -> bool:
+        return isinstance(other, Secret) and other._value == self._value
+
+    def __hash__(self) -> int:
+        return hash(self._value)
+
+
+def redact(text: str) -> str:
+    """
+    Replace passwords in `text`: any in URL userinfo or `Password` elements, and known secret values of at
+    least `MIN_REDACTED_LENGTH` characters wherever they appear, e.g. quoted in a SOAP fault.
+    """
+    text = USERINFO.sub(lambda match: f"{match['prefix']}{REDACTED}@", text)
+    text = PASSWORD_ELEMENT.sub(lambda match: f"{match['open']}{REDACTED}", text)
+    with _lock:
+        values = sorted(_known_values, key=len, reverse=True)
+    for value in values:
+        if len(value) >= MIN_REDACTED_LENGTH:
+            text = text.replace(value, REDACTED)
+    return text
+
+
+class UnresolvedSecret(Exception):
+    pass
+
+
+def is_reference(value: str) -> bool:
+    return value.startswith((SECRET_PREFIX, ENV_PREFIX))
+
+
+def resolve_secret(reference: str) -> Secret:
+    """
+    Resolve a credential reference: `secret:NAME` reads the secret NAME from the secret store,
+    `env:NAME` the environment variable NAME.
+    """
+    if reference.startswith(SECRET_PREFIX):
+        name = reference[len(SECRET_PREFIX):]
+        path = SECRETS_DIR / name
+        try:
+            value = path.read_text().strip()
+        except OSError as e:
+            raise UnresolvedSecret(f"secret {name!r} is not available at {path}: {e.strerror}") from None
+    elif reference.startswith(ENV_PREFIX):
+        name = reference[len(ENV_PREFIX):]
+        value = os.environ.get(name, "")
+    else:
+        raise UnresolvedSecret(f"expected a {SECRET_PREFIX}NAME or {ENV_PREFIX}NAME reference")
+
+    if not name or not value:
+        raise UnresolvedSecret(f"{reference!r} resolved to an empty value")
+    return Secret(value)
@@ -0,0 +1,66 @@
+import logging
+from dataclasses import dataclass
+from typing import Optional
+
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.device import get_capabilities
+from app.error import SoapError, onvif_errors
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+
+@dataclass
+class ServiceEndpoint:
+    namespace: str
+    xaddr: str
+    # `None` when the camera only reported the address (GetCapabilities fallback).
+    version: Optional[tuple[int, int]] = None
+
+
+Services = dict[str, ServiceEndpoint]
+
+
+def get_services(camera: ONVIFCamera) -> Services:
+    """Ask the device service which services the camera exposes, keyed by WSDL namespace."""
+    with onvif_errors("create device service"):
+        device_service = create_service(camera, "devicemgmt")
+    response = call(device_service, "GetServices", {"IncludeCapability": True})
+
+    services = {}
+    for service in response or []:
+        version = (int(service.Version.Major), int(service.Version.Minor)) if service.Version else None
+        services[service.Namespace] = ServiceEndpoint(service.Namespace, service.XAddr, version)
+    return services
+
+
+def get_capabilities_services(camera: ONVIFCamera) -> Services:
+    try:
+        xaddrs = get_capabilities(camera)
+    except SoapError as e:
+        # onvif-zeep already ran GetCapabilities on connect, so what it found then is the last resort.
+        logger.warning(f"GetCapabilities failed ({e}), using the service addresses found on connect.")
+        xaddrs = dict(camera.xaddrs)
+    return {namespace: ServiceEndpoint(namespace, xaddr) for namespace, xaddr in xaddrs.items()}
+
+
+def discover_services(camera: ONVIFCamera) -> Services:
+    """
+    Look up the service endpoints and point the camera's service clients at them.
+    Cameras serve media, PTZ and events at vendor-specific paths, so clients must not guess them.
+    """
+    try:
+        services = get_services(camera)
+    except SoapError as e:
+        # Older Profile S cameras lack GetServices and only describe their services in GetCapabilities.
+        logger.info(f"GetServices failed ({e}), falling back to GetCapabilities.")
+        services = get_capabilities_services(camera)
+
+    # onvif-zeep resolves the address of every service client it creates from `xaddrs`.
+    camera.xaddrs.update({namespace: endpoint.xaddr for namespace, endpoint in services.items()})
+    for endpoint in services.values():
+        version = "%d.%d" % endpoint.version if endpoint.version else "unknown"
+        logger.debug(f"Service {endpoint.namespace} (version {version}) at {endpoint.xaddr}")
+    return services
@@ -0,0 +1,154 @@
+import asyncio
+import json
+import logging
+import threading
+from concurrent.futures import ThreadPoolExecutor
+from datetime import datetime
+from typing import Optional
+
+import requests
+from make87_messages.core.header_pb2 import Header
+from make87_messages.image.compressed.image_jpeg_pb2 import ImageJPEG
+from make87_messages.text.text_plain_pb2 import PlainText
+from requests.auth import HTTPBasicAuth, HTTPDigestAuth
+
+from app.auth import Credentials
+from app.error import AuthError, OnvifError, ParseError, onvif_errors
+from app.logs import camera_context
+
+logger = logging.getLogger(__name__)
+
+JPEG_CONTENT_TYPES = {"image/jpeg", "image/jpg"}
+JPEG_MAGIC = b"\xff\xd8"
+# Appended to the camera's entity path in snapshot responses that carry an error instead of a JPEG.
+ERROR_PATH_SUFFIX = "/snapshot_error"
+# Snapshots fetched at once per camera, and requests that may wait for one before new ones are turned away.
+SNAPSHOT_WORKERS = 2
+MAX_PENDING_REQUESTS = 8
+
+
+def _challenge_auth(response: requests.Response, credentials: Credentials):
+    challenge = response.headers.get("WWW-Authenticate", "")
+    if challenge.lower().startswith("digest"):
+        return HTTPDigestAuth(credentials.username, credentials.password.reveal())
+    return HTTPBasicAuth(credentials.username, credentials.password.reveal())
+
+
+def fetch_snapshot(uri: str, credentials: Credentials, timeout: float = 10.0) -> bytes:
+    """
+    Download a JPEG snapshot. Most cameras answer the first request with a 401 digest challenge,
+    so the request is repeated with whichever scheme the camera asked for.
+    """
+    with onvif_errors("snapshot"):
+        response = requests.get(uri, timeout=timeout)
+        if response.status_code == 401:
+            response = requests.get(uri, auth=_challenge_auth(response, credentials), timeout=timeout)
+        if response.status_code in (401, 403):
+            raise AuthError(f"snapshot: HTTP {response.status_code}")
+        response.raise_for_status()
+
+    content_type = response.headers.get("Content-Type", "").split(";")[0].strip().lower()
+    if content_type not in JPEG_CONTENT_TYPES and not response.content.startswith(JPEG_MAGIC):
+        raise ParseError(f"snapshot: expected a JPEG, got {content_type or 'no content type'}")
+
+    return response.content
+
+
+async def poll_snapshots(topic, uri: str, credentials: Credentials, interval: float, entity_path: str):
+    """Publish a snapshot every `interval` seconds until cancelled."""
+    while True:
+        await asyncio.sleep(interval)
+        try:
+            data = await asyncio.to_thread(fetch_snapshot, uri, credentials)
+        except OnvifError as e:
+            logger.warning(f"Snapshot failed: {e}")
+            continue
+
+        header = Header(entity_path=entity_path)
+        header.timestamp.FromDatetime(datetime.now())
+        await asyncio.to_thread(topic.publish, ImageJPEG(header=header, data=data))
+
+
+class SnapshotResponder:
+    """
+    Answers snapshot requests with a JPEG, echoing the request's `Header.reference_id` as correlation id.
+    When the camera can't deliver one (e.g. while it is unreachable), the response is an error response
+    (see `snapshot_error`), so callers never wait for an answer that doesn't come.
+    """
+
+    def __init__(self, camera_id: str, topic, credentials: Credentials, entity_path: str):
+        self.camera_id = camera_id
+        self.topic = topic
+        self.credentials = credentials
+        self.entity_path = entity_path
+        self.snapshot_uri = None
+        self._lock = threading.Lock()
+        self._executor = ThreadPoolExecutor(max_workers=SNAPSHOT_WORKERS, thread_name_prefix=f"snapshot-{camera_id}")
+        self._pending = threading.BoundedSemaphore(MAX_PENDING_REQUESTS)
+
+    def attach(self, snapshot_uri: str):
+        with self._lock:
+            self.snapshot_uri = snapshot_uri
+
+    def detach(self):
+        with self._lock:
+            self.snapshot_uri = None
+
+    def handle_request(self, message: PlainText):
+        try:
+            # Requests without a "camera" field address every camera.
+            request = json.loads(message.body) if message.body else {}
+            if request.get("camera", self.camera_id) != self.camera_id:
+                return
+        except (ValueError, AttributeError) as e:
+            logger.warning(f"Ignoring malformed snapshot request {message.body!r}: {e}")
+            return
+
+        reference_id = message.header.reference_id
+        # Fetching takes a while; don't hold up the subscriber thread, which serves all cameras. A camera that
+        # hangs on HTTP must not pile up requests either, so those beyond the limit are answered right away.
+        if not self._pending.acquire(blocking=False):
+            with camera_context(self.camera_id):
+                self._publish_error(reference_id, f"more than {MAX_PENDING_REQUESTS} snapshot requests pending")
+            return
+        self._executor.submit(self._respond_pending, reference_id)
+
+    def _respond_pending(self, reference_id: int):
+        try:
+            self._respond(reference_id)
+        finally:
+            self._pending.release()
+
+    def _respond(self, reference_id: int):
+        with camera_context(self.camera_id):
+            with self._lock:
+                snapshot_uri = self.snapshot_uri
+
+            if snapshot_uri is None:
+                self._publish_error(reference_id, "camera is not connected")
+                return
+            try:
+                data = fetch_snapshot(snapshot_uri, self.credentials)
+            except OnvifError as e:
+                self._publish_error(reference_id, str(e))
+                return
+
+            header = Header(entity_path=self.entity_path, reference_id=reference_id)
+            header.timestamp.FromDatetime(datetime.now())
+            self.topic.publish(ImageJPEG(header=header, data=data))
+
+    def _publish_error(self, reference_id: int, reason: str):
+        logger.warning(f"Snapshot request {reference_id} failed: {reason}")
+        header = Header(entity_path=f"{self.entity_path}{ERROR_PATH_SUFFIX}", reference_id=reference_id)
+        header.timestamp.FromDatetime(datetime.now())
+        self.topic.publish(ImageJPEG(header=header, data=json.dumps({"error": reason}).encode("utf-8")))
+
+
+def snapshot_error(response: ImageJPEG) -> Optional[str]:
+    """
+    The reason a snapshot response carries no JPEG, or `None` for a snapshot. Error responses have
+    `/snapshot_error` appended to the camera's entity path, and `{"error": "<reason>"}` as data.
+    """
+    if not response.header.entity_path.endswith(ERROR_PATH_SUFFIX):
+        return None
+    return json.loads(response.data.decode("utf-8"))["error"]
@@ -0,0 +1,112 @@
+from typing import Callable, Optional, Union
+
+import requests
+from lxml import etree
+
+from app.error import SoapFault, fault_error, onvif_errors
+
+SOAP11_NAMESPACE = "http://schemas.xmlsoap.org/soap/envelope/"
+SOAP12_NAMESPACE = "http://www.w3.org/2003/05/soap-envelope"
+
+
+def call(service, operation: str, request=None):
+    """
+    Invoke `operation` on an ONVIF service client, raising an `OnvifError` if it fails.
+    zeep builds the SOAP envelope from the WSDL and `create_service` adds the WS-Security header,
+    so operations only need to provide the request fields.
+    """
+    with onvif_errors(operation):
+        method = getattr(service, operation)
+        return method() if request is None else method(request)
+
+
+def _text(element) -> str:
+    return (element.text or "").strip() if element is not None else ""
+
+
+def parse_soap_fault(body: Union[str, bytes]) -> Optional[SoapFault]:
+    """
+    Extract the Fault from a SOAP 1.2 (or legacy SOAP 1.1) response body.
+    Returns `None` if the body is no SOAP envelope or carries no Fault.
+    """
+    if isinstance(body, str):
+        body = body.encode("utf-8")
+    try:
+        envelope = etree.fromstring(body.strip())
+    except etree.XMLSyntaxError:
+        return None
+
+    for namespace in (SOAP12_NAMESPACE, SOAP11_NAMESPACE):
+        fault = envelope.find(f"{{{namespace}}}Body/{{{namespace}}}Fault")
+        if fault is None:
+            continue
+        if namespace == SOAP11_NAMESPACE:
+            # SOAP 1.1 has no subcodes; ONVIF cameras put the `ter:` code into faultcode itself.
+            return SoapFault(code=_text(fault.find("faultcode")), reason=_text(fault.find("faultstring")))
+
+        code = fault.find(f"{{{namespace}}}Code")
+        subcodes = []
+        subcode = code.find(f"{{{namespace}}}Subcode") if code is not None else None
+        while subcode is not None:
+            subcodes.append(_text(subcode.find(f"{{{namespace}}}Value")))
+            subcode = subcode.find(f"{{{namespace}}}Subcode")
+        return SoapFault(
+            code=_text(code.find(f"{{{namespace}}}Value")) if code is not None else "",
+            # Cameras may send the reason in several languages; the first one will do.
+            reason=_text(fault.find(f"{{{namespace}}}Reason/{{{namespace}}}Text")),
+            subcodes=subcodes,
+        )
+    return None
+
+
+def _append(parent: etree._Element, namespace: str, name: str, value):
+    # Dicts become child elements, lists repeated elements, like zeep maps request dicts.
+    if isinstance(value, list):
+        for item in value:
+            _append(parent, namespace, name, item)
+        return
+    element = etree.SubElement(parent, f"{{{namespace}}}{name}")
+    if isinstance(value, dict):
+        for child_name, child_value in value.items():
+            _append(element, namespace, child_name, child_value)
+    elif isinstance(value, bool):
+        element.text = "true" if value else "false"
+    elif value is not None:
+        element.text = str(value)
+
+
+def build_envelope(namespace: str, operation: str, request: Optional[dict] = None) -> etree._Element:
+    """
+    A SOAP 1.2 envelope for services without a WSDL client, with `request` as `<operation>` in the service's
+    `namespace`. Request elements are qualified with the same namespace, as in the ONVIF service schemas.
+    """
+    root = etree.Element(f"{{{SOAP12_NAMESPACE}}}Envelope", nsmap={"s": SOAP12_NAMESPACE, None: namespace})
+    body = etree.SubElement(root, f"{{{SOAP12_NAMESPACE}}}Body")
+    _append(body, namespace, operation, request or {})
+    return root
+
+
+def operation_name(message: bytes) -> str:
+    """The local name of the first Body child of a request envelope, e.g. `GetProfiles`."""
+    try:
+        body = etree.fromstring(message).find(f"{{{SOAP12_NAMESPACE}}}Body")
+    except etree.XMLSyntaxError:
+        body = None
+    if body is None or len(body) == 0:
+        return "SOAP request"
+    return etree.QName(body[0]).localname
+
+
+def post_soap(
+    post: Callable[[str, bytes, dict], requests.Response], address: str, message: bytes, headers: dict
+) -> requests.Response:
+    """
+    Send a SOAP request with `post` and check the response for a Fault before anything parses it.
+    Every request goes through here: zeep's via `LimitedTransport`, hand-built ones from `Media2Service`.
+    Cameras send Faults with all kinds of HTTP statuses and content types, so the body decides, not the status.
+    """
+    response = post(address, message, headers)
+    fault = parse_soap_fault(response.content) if response.content else None
+    if fault is not None:
+        raise fault_error(fault, operation_name(message))
+    return response
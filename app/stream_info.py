@@ -0,0 +1,199 @@
+import base64
+import logging
+from dataclasses import dataclass
+from typing import Optional
+
+logger = logging.getLogger(__name__)
+
+# NAL unit types of the parameter sets a decoder must be configured with, per codec.
+VPS_NAL_TYPES = {"hevc": 32}
+SPS_NAL_TYPES = {"h264": 7, "hevc": 33}
+PPS_NAL_TYPES = {"h264": 8, "hevc": 34}
+CODEC_NAMES = {"h264": "H264", "hevc": "H265"}
+# H.264 profiles whose SPS carries chroma format, bit depths and scaling matrices (ITU-T H.264, 7.3.2.1.1).
+H264_HIGH_PROFILES = {44, 83, 86, 100, 110, 118, 122, 128, 134, 135, 138, 139, 244}
+# SubWidthC and SubHeightC per chroma_format_idc (ITU-T H.264, table 6-1).
+H264_CHROMA_SUBSAMPLING = {0: (1, 1), 1: (2, 2), 2: (2, 1), 3: (1, 1)}
+
+
+@dataclass
+class VideoStreamInfo:
+    """What a decoder needs before the first frame: the codec, the picture size and the parameter sets."""
+
+    codec: str
+    width: int
+    height: int
+    # NAL units without start codes.
+    sps: bytes
+    pps: bytes
+    # The RFC 6184 profile-level-id, e.g. "640028" for High profile level 4.0; H.264 only.
+    profile_level_id: Optional[str] = None
+    # H.265 only.
+    vps: Optional[bytes] = None
+
+    def to_dict(self) -> dict:
+        info = {
+            "codec": self.codec,
+            "width": self.width,
+            "height": self.height,
+            "sps": base64.b64encode(self.sps).decode("ascii"),
+            "pps": base64.b64encode(self.pps).decode("ascii"),
+            "profile_level_id": self.profile_level_id,
+        }
+        if self.vps is not None:
+            info["vps"] = base64.b64encode(self.vps).decode("ascii")
+        return info
+
+
+@dataclass
+class H264Sps:
+    profile_level_id: str
+    width: int
+    height: int
+
+
+def _rbsp(nal: bytes) -> bytes:
+    """Strip the emulation prevention bytes (00 00 03 becomes 00 00) from a NAL unit."""
+    rbsp = bytearray()
+    zeros = 0
+    for byte in nal:
+        if zeros >= 2 and byte == 3:
+            zeros = 0
+            continue
+        rbsp.append(byte)
+        zeros = zeros + 1 if byte == 0 else 0
+    return bytes(rbsp)
+
+
+class _BitReader:
+    def __init__(self, data: bytes):
+        self.data = data
+        self.position = 0
+
+    def bit(self) -> int:
+        if self.position >= len(self.data) * 8:
+            raise ValueError("SPS is truncated")
+        byte = self.data[self.position // 8]
+        bit = (byte >> (7 - self.position % 8)) & 1
+        self.position += 1
+        return bit
+
+    def bits(self, count: int) -> int:
+        value = 0
+        for _ in range(count):
+            value = value << 1 | self.bit()
+        return value
+
+    def ue(self) -> int:
+        """An unsigned Exp-Golomb code."""
+        leading_zeros = 0
+        while self.bit() == 0:
+            leading_zeros += 1
+            if leading_zeros > 31:
+                raise ValueError("invalid Exp-Golomb code in SPS")
+        return (1 << leading_zeros) - 1 + self.bits(leading_zeros)
+
+    def se(self) -> int:
+        """A signed Exp-Golomb code."""
+        value = self.ue()
+        return (value + 1) // 2 if value % 2 else -(value // 2)
+
+
+def _skip_scaling_list(reader: _BitReader, size: int):
+    last_scale = next_scale = 8
+    for _ in range(size):
+        if next_scale != 0:
+            next_scale = (last_scale + reader.se() + 256) % 256
+        if next_scale != 0:
+            last_scale = next_scale
+
+
+def parse_h264_sps(nal: bytes) -> H264Sps:
+    """
+    Read the profile, level and cropped picture size from an H.264 SPS NAL unit (ITU-T H.264, 7.3.2.1.1).
+    Raises `ValueError` if the SPS is truncated or malformed.
+    """
+    if len(nal) < 4:
+        raise ValueError("SPS is truncated")
+    reader = _BitReader(_rbsp(nal[1:]))
+    profile_idc = reader.bits(8)
+    reader.bits(16)  # constraint_set flags, level_idc
+    reader.ue()  # seq_parameter_set_id
+
+    chroma_format_idc = 1
+    separate_colour_plane = 0
+    if profile_idc in H264_HIGH_PROFILES:
+        chroma_format_idc = reader.ue()
+        if chroma_format_idc not in H264_CHROMA_SUBSAMPLING:
+            raise ValueError(f"invalid chroma_format_idc {chroma_format_idc} in SPS")
+        if chroma_format_idc == 3:
+            separate_colour_plane = reader.bit()
+        reader.ue()  # bit_depth_luma_minus8
+        reader.ue()  # bit_depth_chroma_minus8
+        reader.bit()  # qpprime_y_zero_transform_bypass_flag
+        if reader.bit():  # seq_scaling_matrix_present_flag
+            for i in range(8 if chroma_format_idc != 3 else 12):
+                if reader.bit():
+                    _skip_scaling_list(reader, 16 if i < 6 else 64)
+
+    reader.ue()  # log2_max_frame_num_minus4
+    pic_order_cnt_type = reader.ue()
+    if pic_order_cnt_type == 0:
+        reader.ue()  # log2_max_pic_order_cnt_lsb_minus4
+    elif pic_order_cnt_type == 1:
+        reader.bit()  # delta_pic_order_always_zero_flag
+        reader.se()  # offset_for_non_ref_pic
+        reader.se()  # offset_for_top_to_bottom_field
+        for _ in range(reader.ue()):
+            reader.se()  # offset_for_ref_frame
+    reader.ue()  # max_num_ref_frames
+    reader.bit()  # gaps_in_frame_num_value_allowed_flag
+
+    width_in_mbs = reader.ue() + 1
+    height_in_map_units = reader.ue() + 1
+    frame_mbs_only = reader.bit()
+    if not frame_mbs_only:
+        reader.bit()  # mb_adaptive_frame_field_flag
+    reader.bit()  # direct_8x8_inference_flag
+
+    crop_left = crop_right = crop_top = crop_bottom = 0
+    if reader.bit():  # frame_cropping_flag
+        crop_left, crop_right, crop_top, crop_bottom = (reader.ue() for _ in range(4))
+
+    # Cropping is counted in chroma samples, and in field pairs for interlaced streams.
+    if separate_colour_plane:
+        sub_width, sub_height = 1, 1
+    else:
+        sub_width, sub_height = H264_CHROMA_SUBSAMPLING[chroma_format_idc]
+    field_factor = 2 - frame_mbs_only
+    return H264Sps(
+        profile_level_id=nal[1:4].hex(),
+        width=width_in_mbs * 16 - sub_width * (crop_left + crop_right),
+        height=field_factor * height_in_map_units * 16 - sub_height * field_factor * (crop_top + crop_bottom),
+    )
+
+
+def stream_info_from_parameter_sets(
+    codec: str, parameter_sets: dict[int, bytes], width: int, height: int
+) -> Optional[VideoStreamInfo]:
+    """
+    Assemble the stream info from parameter set NAL units keyed by NAL unit type, or `None` if some are missing.
+    H.264 streams take their size from the SPS; `width` and `height` (from the SDP) stand in for other codecs
+    and for SPS we can't parse.
+    """
+    sps = parameter_sets.get(SPS_NAL_TYPES.get(codec))
+    pps = parameter_sets.get(PPS_NAL_TYPES.get(codec))
+    vps = parameter_sets.get(VPS_NAL_TYPES[codec]) if codec in VPS_NAL_TYPES else None
+    if sps is None or pps is None or (codec in VPS_NAL_TYPES and vps is None):
+        return None
+
+    info = VideoStreamInfo(codec=CODEC_NAMES[codec], width=width, height=height, sps=sps, pps=pps, vps=vps)
+    if codec == "h264":
+        try:
+            parsed = parse_h264_sps(sps)
+        except ValueError as e:
+            logger.warning(f"Could not parse the H.264 SPS ({e}), reporting the SDP resolution.")
+        else:
+            info.profile_level_id = parsed.profile_level_id
+            info.width, info.height = parsed.width, parsed.height
+    return info
@@ -0,0 +1,100 @@
+import logging
+from dataclasses import dataclass, field
+from datetime import datetime, timezone
+
+from onvif import ONVIFCamera
+
+from app.auth import create_service
+from app.error import OnvifError, ParseError, onvif_errors
+from app.soap import call
+
+logger = logging.getLogger(__name__)
+
+TIME_SYNC_MODES = ("off", "manual", "ntp")
+
+
+@dataclass
+class TimeSyncPolicy:
+    # "off" leaves the camera clock alone, "manual" pushes the host time, "ntp" switches the camera to NTP.
+    mode: str = "off"
+    # Seconds the camera clock may deviate from the host before it is corrected.
+    max_skew: float = 2.0
+    # NTP servers configured in "ntp" mode; empty keeps the camera's (or DHCP's) servers.
+    ntp_servers: list[str] = field(default_factory=list)
+
+
+@dataclass
+class CameraClock:
+    utc_time: datetime
+    # "Manual" or "NTP"
+    date_time_type: str
+    time_zone: str = ""
+
+
+def get_system_date_and_time(device_service) -> CameraClock:
+    response = call(device_service, "GetSystemDateAndTime")
+
+    utc = response.UTCDateTime
+    if utc is None:
+        raise ParseError("GetSystemDateAndTime: camera reported no UTC time")
+    date, time = utc.Date, utc.Time
+    return CameraClock(
+        utc_time=datetime(date.Year, date.Month, date.Day, time.Hour, time.Minute, time.Second, tzinfo=timezone.utc),
+        date_time_type=response.DateTimeType,
+        time_zone=response.TimeZone.TZ if response.TimeZone is not None else "",
+    )
+
+
+def set_system_date_and_time(device_service, utc_time: datetime, time_zone: str = ""):
+    """Switch the camera to manual time and set its clock to `utc_time`, keeping its time zone."""
+    request = {
+        "DateTimeType": "Manual",
+        "DaylightSavings": False,
+        "UTCDateTime": {
+            "Date": {"Year": utc_time.year, "Month": utc_time.month, "Day": utc_time.day},
+            "Time": {"Hour": utc_time.hour, "Minute": utc_time.minute, "Second": utc_time.second},
+        },
+    }
+    if time_zone:
+        request["TimeZone"] = {"TZ": time_zone}
+    call(device_service, "SetSystemDateAndTime", request)
+
+
+def enable_ntp(device_service, ntp_servers: list[str], time_zone: str = ""):
+    if ntp_servers:
+        manual = [{"Type": "DNS", "DNSname": server} for server in ntp_servers]
+        call(device_service, "SetNTP", {"FromDHCP": False, "NTPManual": manual})
+    request = {"DateTimeType": "NTP", "DaylightSavings": False}
+    if time_zone:
+        request["TimeZone"] = {"TZ": time_zone}
+    call(device_service, "SetSystemDateAndTime", request)
+
+
+def sync_time(camera: ONVIFCamera, policy: TimeSyncPolicy):
+    """
+    Correct the camera clock when it deviates from the host by more than `max_skew`,
+    so event timestamps can be trusted. Failures are logged; a wrong clock doesn't stop streaming.
+    """
+    if policy.mode == "off":
+        return
+
+    try:
+        with onvif_errors("create device service"):
+            device_service = create_service(camera, "devicemgmt")
+        clock = get_system_date_and_time(device_service)
+        skew = (clock.utc_time - datetime.now(timezone.utc)).total_seconds()
+        if abs(skew) <= policy.max_skew:
+            logger.debug(f"Camera clock is {skew:+.1f}s off, within {policy.max_skew}s.")
+            return
+
+        logger.warning(f"Camera clock is {skew:+.1f}s off ({clock.date_time_type} time), correcting it.")
+        if policy.mode == "ntp":
+            enable_ntp(device_service, policy.ntp_servers, clock.time_zone)
+        else:
+            set_system_date_and_time(device_service, datetime.now(timezone.utc), clock.time_zone)
+
+        # Digest timestamps are offset by the camera clock, so measure it again for all services created from now on.
+        corrected = get_system_date_and_time(device_service)
+        camera.dt_diff = corrected.utc_time - datetime.now(timezone.utc)
+    except OnvifError as e:
+        logger.warning(f"Camera time synchronization failed: {e}")
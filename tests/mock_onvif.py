@@ -0,0 +1,154 @@
+import hashlib
+import threading
+import time
+import xml.etree.ElementTree as ElementTree
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from pathlib import Path
+from typing import Optional
+
+from requests.utils import parse_dict_header
+
+FIXTURES = Path(__file__).parent / "fixtures"
+SOAP12_NAMESPACE = "http://www.w3.org/2003/05/soap-envelope"
+DIGEST_REALM = "IP Camera"
+DIGEST_NONCE = "4e4a4262634b6f774e6a41794e446b774f513d3d"
+
+
+def operation_name(envelope: bytes) -> str:
+    """The local name of the first Body child, e.g. `GetProfiles`."""
+    body = ElementTree.fromstring(envelope).find(f"{{{SOAP12_NAMESPACE}}}Body")
+    return body[0].tag.rsplit("}", 1)[-1]
+
+
+def _md5(*parts: str) -> str:
+    return hashlib.md5(":".join(parts).encode("utf-8")).hexdigest()
+
+
+def digest_is_valid(authorization: Optional[str], method: str, username: str, password: str) -> bool:
+    """Check an RFC 7616 `Authorization: Digest` header (MD5, qop=auth) against our credentials."""
+    if not authorization or not authorization.startswith("Digest "):
+        return False
+    fields = parse_dict_header(authorization[len("Digest ") :])
+    if fields.get("username") != username or fields.get("nonce") != DIGEST_NONCE:
+        return False
+    ha1 = _md5(username, DIGEST_REALM, password)
+    ha2 = _md5(method, fields.get("uri", ""))
+    expected = _md5(ha1, DIGEST_NONCE, fields.get("nc", ""), fields.get("cnonce", ""), "auth", ha2)
+    return fields.get("response") == expected
+
+
+class MockOnvifServer:
+    """
+    In-process HTTP server answering ONVIF requests with the canned responses in `fixtures/<brand>/<Operation>.xml`.
+    Every service lives on the same server, so the `{xaddr}` placeholder in responses is replaced by its address.
+    Received envelopes are kept per operation so tests can assert what the clients sent, and the HTTP
+    `Authorization` header of every request in `authorizations`. `max_in_flight` is the most requests
+    that were ever being answered at once.
+    """
+
+    def __init__(self, brand: str):
+        self.fixtures = FIXTURES / brand
+        self.requests: dict[str, list[bytes]] = {}
+        # Every operation received, in order.
+        self.operations: list[str] = []
+        self.authorizations: list[tuple[str, Optional[str]]] = []
+        self._faults: dict[str, tuple[Path, int]] = {}
+        self._digest_paths: dict[str, tuple[str, str]] = {}
+        self._delays: dict[str, float] = {}
+        self.in_flight = 0
+        self.max_in_flight = 0
+        self._lock = threading.Lock()
+        self._server = ThreadingHTTPServer(("127.0.0.1", 0), self._handler())
+        self._thread = threading.Thread(target=self._server.serve_forever, daemon=True)
+
+    @property
+    def port(self) -> int:
+        return self._server.server_address[1]
+
+    @property
+    def xaddr(self) -> str:
+        return f"http://127.0.0.1:{self.port}"
+
+    def fail(self, operation: str, fixture: Path, status: int = 400):
+        """Answer `operation` with the SOAP Fault in `fixture` from now on."""
+        self._faults[operation] = (fixture, status)
+
+    def recover(self, operation: str):
+        """Answer `operation` with its canned response again."""
+        self._faults.pop(operation, None)
+
+    def require_digest(self, path: str, username: str, password: str):
+        """Reject requests to `path` (e.g. `/onvif/Media`) without valid HTTP Digest credentials."""
+        self._digest_paths[path] = (username, password)
+
+    def delay(self, operation: str, seconds: float):
+        """Answer `operation` only after `seconds`, like a slow camera."""
+        self._delays[operation] = seconds
+
+    def last_request(self, operation: str) -> bytes:
+        with self._lock:
+            return self.requests[operation][-1]
+
+    def __enter__(self):
+        self._thread.start()
+        return self
+
+    def __exit__(self, *exc_info):
+        self._server.shutdown()
+        self._server.server_close()
+        self._thread.join()
+
+    def _respond(self, envelope: bytes) -> tuple[int, Optional[bytes]]:
+        operation = operation_name(envelope)
+        with self._lock:
+            self.requests.setdefault(operation, []).append(envelope)
+            self.operations.append(operation)
+            self.in_flight += 1
+            self.max_in_flight = max(self.max_in_flight, self.in_flight)
+        try:
+            time.sleep(self._delays.get(operation, 0))
+        finally:
+            with self._lock:
+                self.in_flight -= 1
+
+        if operation in self._faults:
+            fixture, status = self._faults[operation]
+        else:
+            fixture, status = self.fixtures / f"{operation}.xml", 200
+        if not fixture.exists():
+            return 500, None
+        return status, fixture.read_text().replace("{xaddr}", self.xaddr).encode("utf-8")
+
+    def _handler(self):
+        server = self
+
+        class Handler(BaseHTTPRequestHandler):
+            def do_POST(self):
+                envelope = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+                authorization = self.headers.get("Authorization")
+                with server._lock:
+                    server.authorizations.append((self.path, authorization))
+
+                if self.path in server._digest_paths:
+                    username, password = server._digest_paths[self.path]
+                    if not digest_is_valid(authorization, "POST", username, password):
+                        self.send_response(401)
+                        self.send_header(
+                            "WWW-Authenticate", f'Digest realm="{DIGEST_REALM}", nonce="{DIGEST_NONCE}", qop="auth"'
+                        )
+                        self.send_header("Content-Length", "0")
+                        self.end_headers()
+                        return
+
+                status, response = server._respond(envelope)
+                self.send_response(status)
+                self.send_header("Content-Type", "application/soap+xml; charset=utf-8")
+                self.send_header("Content-Length", str(len(response or b"")))
+                self.end_headers()
+                if response:
+                    self.wfile.write(response)
+
+            def log_message(self, format, *args):
+                pass
+
+        return Handler
@@ -0,0 +1,28 @@
+import base64
+from datetime import datetime, timedelta, timezone
+
+from app.auth import WSSE_NAMESPACE, WSU_NAMESPACE, Credentials, format_created, password_digest, security_header
+
+# Example from the ONVIF Application Programmer's Guide, section 6.1.1.3.
+NONCE = base64.b64decode("LKqI6G/AikKCQrN0zqZFlg==")
+CREATED = "2010-09-16T07:50:45Z"
+PASSWORD = "userpassword"
+EXPECTED_DIGEST = "tuOSpGlFlIXsozq4HFNeeGeFLEI="
+
+
+def test_password_digest_matches_known_vector():
+    assert password_digest(NONCE, CREATED, PASSWORD) == EXPECTED_DIGEST
+
+
+def test_security_header_contains_digest_nonce_and_created():
+    header = security_header(Credentials(username="user", password=PASSWORD), nonce=NONCE, created=CREATED)
+
+    assert header.findtext(f".//{{{WSSE_NAMESPACE}}}Username") == "user"
+    assert header.findtext(f".//{{{WSSE_NAMESPACE}}}Password") == EXPECTED_DIGEST
+    assert header.findtext(f".//{{{WSSE_NAMESPACE}}}Nonce") == "LKqI6G/AikKCQrN0zqZFlg=="
+    assert header.findtext(f".//{{{WSU_NAMESPACE}}}Created") == CREATED
+
+
+def test_created_is_formatted_as_utc():
+    local = datetime(2010, 9, 16, 9, 50, 45, 123456, tzinfo=timezone(timedelta(hours=2)))
+    assert format_created(local) == CREATED
@@ -0,0 +1,134 @@
+import json
+from datetime import datetime, timezone
+from types import SimpleNamespace
+
+import pytest
+from lxml import etree
+
+from app.auth import Credentials, connect
+from app.error import SoapError
+from app.events import (
+    DigitalInputChanged,
+    MotionDetector,
+    MotionStarted,
+    MotionStopped,
+    PullPointSubscription,
+    UnknownEvent,
+    parse_notification,
+    topic_path,
+)
+from tests.mock_onvif import FIXTURES, MockOnvifServer
+
+WSNT_NAMESPACE = "http://docs.oasis-open.org/wsn/b-2"
+SCHEMA_NAMESPACE = "http://www.onvif.org/ver10/schema"
+
+# The kinds each brand's PullMessages response should be read as, in order.
+EXPECTED = {
+    "hikvision": [
+        DigitalInputChanged(input_token="AlarmIn_1", active=False),
+        MotionStarted(MotionDetector.CELL_MOTION, video_source="VideoSourceToken", rule="MyMotionDetectorRule"),
+        MotionStarted(MotionDetector.MOTION_ALARM, video_source="VideoSource_1"),
+        MotionStopped(MotionDetector.CELL_MOTION, video_source="VideoSourceToken", rule="MyMotionDetectorRule"),
+        UnknownEvent(
+            source={
+                "VideoSourceConfigurationToken": "VideoSourceToken",
+                "VideoAnalyticsConfigurationToken": "VideoAnalyticsToken",
+                "Rule": "MyTamperDetectorRule",
+            },
+            data={"IsTamper": "true"},
+        ),
+    ],
+    "axis": [
+        MotionStarted(MotionDetector.MOTION_ALARM, video_source="0"),
+        UnknownEvent(source={"port": "1"}, data={"state": "1"}),
+        DigitalInputChanged(input_token="1", active=True),
+        MotionStopped(MotionDetector.MOTION_ALARM, video_source="0"),
+    ],
+    "uniview": [
+        MotionStarted(MotionDetector.CELL_MOTION, video_source="0", rule="MotionDetection"),
+        DigitalInputChanged(input_token="AlarmIn0", active=True),
+        MotionStopped(MotionDetector.MOTION_ALARM, video_source="0"),
+    ],
+}
+
+
+def notifications(brand: str) -> list[SimpleNamespace]:
+    """The notifications in `fixtures/<brand>/PullMessages.xml`, shaped as zeep leaves unmapped `tt:Message`s."""
+    envelope = etree.parse(str(FIXTURES / brand / "PullMessages.xml"))
+    payload = f"{{{WSNT_NAMESPACE}}}Message/{{{SCHEMA_NAMESPACE}}}Message"
+    return [
+        SimpleNamespace(
+            Topic=SimpleNamespace(_value_1=message.findtext(f"{{{WSNT_NAMESPACE}}}Topic")),
+            Message=SimpleNamespace(_value_1=message.find(payload)),
+        )
+        for message in envelope.iter(f"{{{WSNT_NAMESPACE}}}NotificationMessage")
+    ]
+
+
+@pytest.mark.parametrize("brand", sorted(EXPECTED))
+def test_notifications_are_typed(brand):
+    events = [parse_notification(notification) for notification in notifications(brand)]
+
+    assert [event.kind for event in events] == EXPECTED[brand]
+
+
+def test_topic_prefixes_are_ignored():
+    assert topic_path("tns1:RuleEngine/CellMotionDetector/Motion") == "RuleEngine/CellMotionDetector/Motion"
+    assert topic_path("tns:RuleEngine/tns:CellMotionDetector/tns:Motion//.") == "RuleEngine/CellMotionDetector/Motion"
+    assert topic_path("tns1:Device/tnsaxis:IO/Port") == "Device/IO/Port"
+
+
+def test_typed_form_is_published():
+    motion, _, stopped = [parse_notification(notification) for notification in notifications("uniview")]
+
+    assert json.loads(motion.to_json()) == {
+        "type": "MotionStarted",
+        "topic": "tns:RuleEngine/tns:CellMotionDetector/tns:Motion//.",
+        "utc_time": "2024-06-11T16:02:04+00:00",
+        "detector": "cell_motion",
+        "video_source": "0",
+        "rule": "MotionDetection",
+    }
+    assert json.loads(stopped.to_json())["type"] == "MotionStopped"
+
+
+def test_unknown_topics_keep_the_raw_items():
+    tamper = parse_notification(notifications("hikvision")[-1])
+
+    message = json.loads(tamper.to_json())
+    assert message["type"] == "UnknownEvent"
+    assert message["data"] == {"IsTamper": "true"}
+    assert message["source"]["Rule"] == "MyTamperDetectorRule"
+
+
+@pytest.fixture
+def server():
+    with MockOnvifServer("hikvision") as server:
+        yield server
+
+
+@pytest.fixture
+def subscription(server):
+    return PullPointSubscription(connect("127.0.0.1", server.port, Credentials(username="admin", password="password")))
+
+
+def test_failed_pull_unsubscribes_before_subscribing_again(server, subscription):
+    server.fail("PullMessages", FIXTURES / "faults" / "action_not_supported.xml")
+    with pytest.raises(SoapError):
+        subscription.pull()
+
+    server.recover("PullMessages")
+    assert subscription.pull()
+
+    subscribing = [op for op in server.operations if op in ("CreatePullPointSubscription", "Unsubscribe")]
+    assert subscribing == ["CreatePullPointSubscription", "Unsubscribe", "CreatePullPointSubscription"]
+
+
+def test_failed_renewal_unsubscribes_before_subscribing_again(server, subscription):
+    subscription.pull()
+    server.fail("Renew", FIXTURES / "faults" / "action_not_supported.xml")
+    subscription.renew_at = datetime.now(timezone.utc)
+    subscription.pull()
+
+    subscribing = [op for op in server.operations if op in ("CreatePullPointSubscription", "Renew", "Unsubscribe")]
+    assert subscribing == ["CreatePullPointSubscription", "Renew", "Unsubscribe", "CreatePullPointSubscription"]
@@ -0,0 +1,38 @@
+from app.frame_queue import FrameRateLimiter
+
+FPS = 30
+GOP = 30
+
+
+def run(limiter: FrameRateLimiter, seconds: int, disposable=lambda index: False) -> list[int]:
+    """Feed a 30 fps stream with a keyframe every second and return the indices of the frames let through."""
+    passed = []
+    for index in range(seconds * FPS):
+        if limiter.allow(index / FPS, is_keyframe=index % GOP == 0, disposable=disposable(index)):
+            passed.append(index)
+    return passed
+
+
+def test_output_rate_approximates_the_cap():
+    passed = run(FrameRateLimiter(10), seconds=20)
+
+    assert 8 * 20 <= len(passed) <= 12 * 20
+
+
+def test_keyframes_always_pass_and_gops_have_no_holes():
+    passed = set(run(FrameRateLimiter(2), seconds=10))
+
+    for gop_start in range(0, 10 * FPS, GOP):
+        assert gop_start in passed
+        kept = [index for index in range(gop_start, gop_start + GOP) if index in passed]
+        # Reference frames decode only if every frame before them in the GOP was kept.
+        assert kept == list(range(gop_start, gop_start + len(kept)))
+
+
+def test_disposable_frames_are_dropped_on_their_own():
+    # Every other frame is a non-reference frame, so halving the rate needs no GOP to be cut short.
+    limiter = FrameRateLimiter(15)
+    passed = run(limiter, seconds=10, disposable=lambda index: index % 2 == 1)
+
+    assert all(index in passed for index in range(0, 10 * FPS, 2))
+    assert 14 * 10 <= len(passed) <= 16 * 10
@@ -0,0 +1,69 @@
+import pytest
+from lxml import etree
+
+from app.auth import WSSE_NAMESPACE, Credentials, connect
+from app.error import SoapError
+from app.media import create_media_service, get_profiles, get_snapshot_uri, get_stream_uri
+from app.media2 import MEDIA2_NAMESPACE, Media2Service
+from app.services import discover_services
+from tests.mock_onvif import FIXTURES, MockOnvifServer
+
+
+@pytest.fixture
+def server():
+    with MockOnvifServer("media2") as server:
+        yield server
+
+
+@pytest.fixture
+def media_service(server):
+    camera = connect("127.0.0.1", server.port, Credentials(username="admin", password="password"))
+    return create_media_service(camera, discover_services(camera))
+
+
+def test_media2_only_cameras_get_a_media2_client(media_service):
+    assert isinstance(media_service, Media2Service)
+    assert media_service.xaddr.endswith("/onvif/Media2")
+
+
+def test_media2_profiles_are_parsed(media_service):
+    main, sub = get_profiles(media_service)
+
+    assert (main.token, main.name, main.resolution, main.encoding, main.framerate) == (
+        "Profile_1",
+        "mainStream",
+        (3840, 2160),
+        "H265",
+        20.0,
+    )
+    assert (main.video_source_token, main.video_source_configuration_token) == ("VideoSource_1", "VideoSourceToken")
+    assert main.ptz_configuration_token == "PTZToken"
+    assert (sub.resolution, sub.encoding, sub.ptz_configuration_token) == ((640, 360), "H264", None)
+
+
+def test_media2_stream_uri_request_envelope(server, media_service):
+    uri = get_stream_uri(media_service, "Profile_1")
+    assert uri == "rtsp://192.168.1.64:554/Streaming/Channels/101?transportmode=unicast&profile=Profile_1"
+
+    envelope = etree.fromstring(server.last_request("GetStreamUri"))
+    request = envelope.find(f".//{{{MEDIA2_NAMESPACE}}}GetStreamUri")
+    assert request.findtext(f"{{{MEDIA2_NAMESPACE}}}Protocol") == "RTSP"
+    assert request.findtext(f"{{{MEDIA2_NAMESPACE}}}ProfileToken") == "Profile_1"
+    assert envelope.find(f".//{{{WSSE_NAMESPACE}}}UsernameToken") is not None
+
+
+def test_media2_snapshot_uri(media_service):
+    assert get_snapshot_uri(media_service, "Profile_1") == "http://192.168.1.64/onvif-http/snapshot?Profile_1"
+
+
+def test_media2_faults_raise_soap_error(server, media_service):
+    server.fail("GetStreamUri", FIXTURES / "faults" / "no_profile.xml")
+
+    with pytest.raises(SoapError) as error:
+        get_stream_uri(media_service, "ProfileToken")
+    assert "NoProfile" in str(error.value)
+
+
+def test_unimplemented_media2_operations_are_unsupported(media_service):
+    with pytest.raises(SoapError):
+        media_service.GetOSDOptions({"ConfigurationToken": "VideoSourceToken"})
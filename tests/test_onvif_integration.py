@@ -0,0 +1,137 @@
+from concurrent.futures import ThreadPoolExecutor
+
+import pytest
+from lxml import etree
+
+from app.auth import PASSWORD_DIGEST_TYPE, WSSE_NAMESPACE, WSU_NAMESPACE, Credentials, connect, create_service
+from app.device import get_device_information
+from app.error import AuthError, SoapError
+from app.media import get_profiles, get_stream_uri
+from tests.mock_onvif import FIXTURES, MockOnvifServer
+
+MEDIA_NAMESPACE = "http://www.onvif.org/ver10/media/wsdl"
+SCHEMA_NAMESPACE = "http://www.onvif.org/ver10/schema"
+
+# What the driver should read from each brand's canned responses.
+EXPECTED = {
+    "hikvision": {
+        "manufacturer": "HIKVISION",
+        "model": "DS-2CD2143G0-I",
+        "profiles": [
+            ("Profile_1", "mainStream", (2560, 1440), "H264"),
+            ("Profile_2", "subStream", (640, 360), "H264"),
+        ],
+        "stream_uri": "rtsp://192.168.1.64:554/Streaming/Channels/101?transportmode=unicast&profile=Profile_1",
+        "media_path": "/onvif/Media",
+    },
+    "axis": {
+        "manufacturer": "AXIS",
+        "model": "M3106-L Mk II",
+        "profiles": [
+            ("profile_1_h264", "profile_1 h264", (2560, 1440), "H264"),
+            ("profile_1_jpeg", "profile_1 jpeg", (1280, 720), "JPEG"),
+        ],
+        "stream_uri": (
+            "rtsp://192.168.0.90/onvif-media/media.amp?profile=profile_1_h264&sessiontimeout=60&streamtype=unicast"
+        ),
+        "media_path": "/onvif/services",
+    },
+}
+
+
+@pytest.fixture(params=sorted(EXPECTED))
+def brand(request):
+    return request.param
+
+
+@pytest.fixture
+def server(brand):
+    with MockOnvifServer(brand) as server:
+        yield server
+
+
+@pytest.fixture
+def camera(server):
+    return connect("127.0.0.1", server.port, Credentials(username="admin", password="password"))
+
+
+def test_device_information_is_parsed(brand, camera):
+    info = get_device_information(camera)
+
+    assert info.manufacturer == EXPECTED[brand]["manufacturer"]
+    assert info.model == EXPECTED[brand]["model"]
+
+
+def test_profiles_are_parsed(brand, camera):
+    profiles = get_profiles(create_service(camera, "media"))
+
+    assert [(p.token, p.name, p.resolution, p.encoding) for p in profiles] == EXPECTED[brand]["profiles"]
+
+
+def test_stream_uri_request_envelope(brand, server, camera):
+    profile_token = EXPECTED[brand]["profiles"][0][0]
+    uri = get_stream_uri(create_service(camera, "media"), profile_token)
+    assert uri == EXPECTED[brand]["stream_uri"]
+
+    envelope = etree.fromstring(server.last_request("GetStreamUri"))
+    request = envelope.find(f".//{{{MEDIA_NAMESPACE}}}GetStreamUri")
+    assert request.findtext(f"{{{MEDIA_NAMESPACE}}}ProfileToken") == profile_token
+    setup = request.find(f"{{{MEDIA_NAMESPACE}}}StreamSetup")
+    assert setup.findtext(f"{{{SCHEMA_NAMESPACE}}}Stream") == "RTP-Unicast"
+    assert setup.findtext(f"{{{SCHEMA_NAMESPACE}}}Transport/{{{SCHEMA_NAMESPACE}}}Protocol") == "RTSP"
+
+    username_token = envelope.find(f".//{{{WSSE_NAMESPACE}}}UsernameToken")
+    assert username_token.findtext(f"{{{WSSE_NAMESPACE}}}Username") == "admin"
+    password = username_token.find(f"{{{WSSE_NAMESPACE}}}Password")
+    assert password.get("Type") == PASSWORD_DIGEST_TYPE
+    # Only the digest goes over the wire.
+    assert password.text and password.text != "password"
+    assert username_token.findtext(f"{{{WSSE_NAMESPACE}}}Nonce")
+    assert username_token.findtext(f"{{{WSU_NAMESPACE}}}Created")
+
+
+def test_not_authorized_fault_raises_auth_error(server, camera):
+    server.fail("GetDeviceInformation", FIXTURES / "faults" / "not_authorized.xml")
+
+    with pytest.raises(AuthError):
+        get_device_information(camera)
+
+
+def test_other_faults_raise_soap_error(server, camera):
+    server.fail("GetStreamUri", FIXTURES / "faults" / "no_profile.xml")
+
+    with pytest.raises(SoapError) as error:
+        get_stream_uri(create_service(camera, "media"), "ProfileToken")
+    assert "NoProfile" in str(error.value)
+
+
+def test_http_digest_is_answered_per_endpoint(brand, server, camera):
+    media_path = EXPECTED[brand]["media_path"]
+    server.require_digest(media_path, "admin", "password")
+    media_service = create_service(camera, "media")
+
+    assert get_profiles(media_service)
+    assert get_profiles(media_service)
+    get_device_information(camera)
+
+    media = [authorization for path, authorization in server.authorizations if path == media_path]
+    # The first envelope is sent again after the challenge, later ones carry the digest right away.
+    assert media[0] is None
+    assert all(authorization.startswith("Digest ") for authorization in media[1:])
+    assert len(media) == 3
+    assert all(authorization is None for path, authorization in server.authorizations if path != media_path)
+
+
+@pytest.mark.parametrize("limit", [1, 3])
+def test_concurrent_requests_are_limited_per_camera(server, limit):
+    camera = connect(
+        "127.0.0.1", server.port, Credentials(username="admin", password="password"), max_concurrent_requests=limit
+    )
+    media_service = create_service(camera, "media")
+    server.delay("GetProfiles", 0.2)
+
+    with ThreadPoolExecutor(max_workers=6) as executor:
+        results = list(executor.map(lambda _: get_profiles(media_service), range(6)))
+
+    assert all(results)
+    assert server.max_in_flight == limit
@@ -0,0 +1,60 @@
+import requests
+
+from app.connection import ConnectionState
+from app.health import CameraStatus
+from app.prometheus import CONTENT_TYPE, MetricsServer, render_metrics
+
+
+def streaming_camera(camera_id: str = "front") -> CameraStatus:
+    status = CameraStatus(camera_id, entity_path=f"/camera/{camera_id}")
+    for state in (ConnectionState.AUTHENTICATING, ConnectionState.DISCOVERING, ConnectionState.STREAMING):
+        status.connection_changed(state)
+    status.frame_published(1200)
+    status.frame_published(800)
+    status.frame_published(300, stream="sub")
+    status.stream_measured("main", 25.0)
+    return status
+
+
+def test_stream_counters_are_labelled_by_camera_and_stream():
+    lines = render_metrics([streaming_camera().metrics()]).splitlines()
+
+    assert 'onvif_camera_frames_published_total{camera="front",stream="main"} 2' in lines
+    assert 'onvif_camera_frames_published_total{camera="front",stream="sub"} 1' in lines
+    assert 'onvif_camera_published_bytes_total{camera="front",stream="main"} 2000' in lines
+    assert 'onvif_camera_fps{camera="front",stream="main"} 25.0' in lines
+    assert "# TYPE onvif_camera_frames_published_total counter" in lines
+
+
+def test_connection_state_is_one_gauge_per_state():
+    status = streaming_camera()
+    status.connection_changed(ConnectionState.RECONNECTING)
+    lines = render_metrics([status.metrics()]).splitlines()
+
+    states = [line for line in lines if line.startswith("onvif_camera_connection_state{")]
+    assert len(states) == len(ConnectionState)
+    assert 'onvif_camera_connection_state{camera="front",state="reconnecting"} 1' in states
+    assert sum(int(line.rsplit(" ", 1)[1]) for line in states) == 1
+    assert 'onvif_camera_reconnects_total{camera="front"} 1' in lines
+    # A camera that is not streaming delivers no frames.
+    assert 'onvif_camera_fps{camera="front",stream="main"} 0.0' in lines
+
+
+def test_label_values_are_escaped():
+    text = render_metrics([CameraStatus('gate "north"', entity_path="/camera/gate").metrics()])
+
+    assert 'onvif_camera_reconnects_total{camera="gate \\"north\\""} 0' in text.splitlines()
+
+
+def test_server_serves_metrics_until_closed():
+    server = MetricsServer(0, collect=lambda: render_metrics([streaming_camera().metrics()]), host="127.0.0.1")
+    server.start()
+    try:
+        response = requests.get(f"http://127.0.0.1:{server.port}/metrics", timeout=5)
+        assert response.status_code == 200
+        assert response.headers["Content-Type"] == CONTENT_TYPE
+        assert 'onvif_camera_ptz_commands_total{camera="front"} 0' in response.text
+
+        assert requests.get(f"http://127.0.0.1:{server.port}/", timeout=5).status_code == 404
+    finally:
+        server.close()
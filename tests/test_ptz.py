@@ -0,0 +1,65 @@
+import threading
+import time
+
+from app.ptz import MoveCoalescer
+
+
+class RecordingCamera:
+    def __init__(self):
+        self.moves = []
+        self.stops = 0
+        self.calls = []
+        self._lock = threading.Lock()
+
+    def move(self, pan, tilt, zoom):
+        with self._lock:
+            self.moves.append((pan, tilt, zoom))
+            self.calls.append("move")
+
+    def stop(self):
+        with self._lock:
+            self.stops += 1
+            self.calls.append("stop")
+
+
+def test_burst_of_velocities_is_coalesced():
+    camera = RecordingCamera()
+    coalescer = MoveCoalescer(0.1, camera.move, camera.stop)
+
+    start = time.monotonic()
+    for i in range(1, 101):
+        coalescer.move(i / 100, 0.0, 0.0)
+        time.sleep(0.002)
+    elapsed = time.monotonic() - start
+    time.sleep(0.25)
+
+    # One move per started interval, plus the latest velocity flushed at the end.
+    assert len(camera.moves) <= int(elapsed / 0.1) + 2
+    assert camera.moves[0] == (0.01, 0.0, 0.0)
+    assert camera.moves[-1] == (1.0, 0.0, 0.0)
+
+
+def test_stop_is_sent_immediately_and_discards_the_pending_move():
+    camera = RecordingCamera()
+    coalescer = MoveCoalescer(0.1, camera.move, camera.stop)
+
+    coalescer.move(0.5, 0.0, 0.0)
+    coalescer.move(0.6, 0.0, 0.0)
+    coalescer.stop()
+    assert camera.calls == ["move", "stop"]
+
+    time.sleep(0.2)
+    assert camera.calls == ["move", "stop"]
+
+
+def test_cancel_drops_the_pending_move():
+    camera = RecordingCamera()
+    coalescer = MoveCoalescer(0.1, camera.move, camera.stop)
+
+    coalescer.move(0.5, 0.0, 0.0)
+    coalescer.move(0.6, 0.0, 0.0)
+    coalescer.cancel()
+    time.sleep(0.2)
+
+    assert camera.moves == [(0.5, 0.0, 0.0)]
+    assert camera.stops == 0
@@ -0,0 +1,70 @@
+import socket
+import threading
+from types import SimpleNamespace
+
+from app import snapshot
+from app.auth import Credentials
+from app.snapshot import MAX_PENDING_REQUESTS, SnapshotResponder, snapshot_error
+
+ENTITY_PATH = "/camera/front"
+
+
+class Topic:
+    def __init__(self):
+        self.published = []
+
+    def publish(self, message):
+        self.published.append(message)
+
+
+def unused_port() -> int:
+    with socket.socket() as sock:
+        sock.bind(("127.0.0.1", 0))
+        return sock.getsockname()[1]
+
+
+def responder() -> tuple[SnapshotResponder, Topic]:
+    topic = Topic()
+    credentials = Credentials(username="admin", password="password")
+    return SnapshotResponder("front", topic, credentials, ENTITY_PATH), topic
+
+
+def test_unreachable_camera_is_answered_with_an_error():
+    snapshots, topic = responder()
+    snapshots.attach(f"http://127.0.0.1:{unused_port()}/snapshot.jpg")
+
+    snapshots._respond(reference_id=7)
+
+    [response] = topic.published
+    assert response.header.reference_id == 7
+    assert response.header.entity_path == f"{ENTITY_PATH}/snapshot_error"
+    assert snapshot_error(response).startswith("snapshot: ")
+
+
+def test_disconnected_camera_is_answered_with_an_error():
+    snapshots, topic = responder()
+
+    snapshots._respond(reference_id=8)
+
+    [response] = topic.published
+    assert response.header.reference_id == 8
+    assert snapshot_error(response) == "camera is not connected"
+
+
+def test_requests_beyond_the_limit_are_answered_with_an_error(monkeypatch):
+    hanging = threading.Event()
+    monkeypatch.setattr(snapshot, "fetch_snapshot", lambda uri, credentials: hanging.wait() and b"\xff\xd8")
+    snapshots, topic = responder()
+    snapshots.attach("http://127.0.0.1/snapshot.jpg")
+
+    for reference_id in range(MAX_PENDING_REQUESTS + 1):
+        snapshots.handle_request(SimpleNamespace(body="", header=SimpleNamespace(reference_id=reference_id)))
+
+    [rejected] = topic.published
+    assert rejected.header.reference_id == MAX_PENDING_REQUESTS
+    assert "pending" in snapshot_error(rejected)
+
+    hanging.set()
+    snapshots._executor.shutdown(wait=True)
+    assert len(topic.published) == MAX_PENDING_REQUESTS + 1
+    assert all(snapshot_error(response) is None for response in topic.published[1:])
@@ -0,0 +1,57 @@
+from pathlib import Path
+
+from app.soap import parse_soap_fault
+
+FIXTURES = Path(__file__).parent / "fixtures" / "faults"
+
+
+def fixture(name: str) -> str:
+    return (FIXTURES / name).read_text()
+
+
+def test_not_authorized_fault_is_an_auth_fault():
+    fault = parse_soap_fault(fixture("not_authorized.xml"))
+
+    assert fault.code == "env:Sender"
+    assert fault.subcodes == ["ter:NotAuthorized"]
+    assert fault.reason == "Sender not Authorized"
+    assert fault.is_auth
+
+
+def test_nested_subcodes_are_collected_in_order():
+    fault = parse_soap_fault(fixture("no_profile.xml"))
+
+    assert fault.code == "SOAP-ENV:Sender"
+    assert fault.subcodes == ["ter:InvalidArgVal", "ter:NoProfile"]
+    assert fault.reason == "The requested profile token ProfileToken does not exist."
+    assert not fault.is_auth
+
+
+def test_receiver_fault_is_not_an_auth_fault():
+    fault = parse_soap_fault(fixture("action_not_supported.xml"))
+
+    assert fault.code == "s:Receiver"
+    assert fault.subcodes == ["ter:ActionNotSupported", "ter:NoImagingForSource"]
+    assert str(fault) == (
+        "The requested VideoSource does not support imaging settings. (Receiver/ActionNotSupported/NoImagingForSource)"
+    )
+    assert not fault.is_auth
+
+
+def test_soap11_fault_code_is_checked_for_auth():
+    fault = parse_soap_fault(fixture("soap11_not_authorized.xml"))
+
+    assert fault.code == "ter:NotAuthorized"
+    assert fault.reason == "Authentication failed"
+    assert fault.is_auth
+
+
+def test_non_fault_bodies_yield_none():
+    response = (
+        '<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Body>'
+        '<tds:GetSystemDateAndTimeResponse xmlns:tds="http://www.onvif.org/ver10/device/wsdl"/>'
+        "</s:Body></s:Envelope>"
+    )
+    assert parse_soap_fault(response) is None
+    assert parse_soap_fault("<html><body>401 Unauthorized</body></html>") is None
+    assert parse_soap_fault("not xml at all") is None
@@ -0,0 +1,43 @@
+import base64
+
+from app.stream_info import parse_h264_sps, stream_info_from_parameter_sets
+
+# sprop-parameter-sets as cameras announce them in the SDP.
+HIGH_1080P_SPS = base64.b64decode("Z2QAKKzZQHgCJ+WEAAADAAQAAAMA8Dxgxlg=")
+MAIN_1080P_SPS = base64.b64decode("Z00AKp2oHgCJ+WbgICAoAAADAAgAAAMAyCA=")
+HIGH_720P_SPS = base64.b64decode("Z2QAH6zZQFAFuwEQAAADABAAAAMDIPGDGWA=")
+PPS = base64.b64decode("aOvjyyLA")
+
+
+def test_high_profile_sps_is_cropped_to_1080_lines():
+    sps = parse_h264_sps(HIGH_1080P_SPS)
+
+    assert sps.profile_level_id == "640028"
+    assert (sps.width, sps.height) == (1920, 1080)
+
+
+def test_main_profile_sps():
+    sps = parse_h264_sps(MAIN_1080P_SPS)
+
+    assert sps.profile_level_id == "4d002a"
+    assert (sps.width, sps.height) == (1920, 1080)
+
+
+def test_stream_info_takes_the_size_from_the_sps():
+    # The SDP size stands in only for what the SPS doesn't say.
+    info = stream_info_from_parameter_sets("h264", {7: HIGH_720P_SPS, 8: PPS}, width=1920, height=1080)
+
+    assert (info.codec, info.width, info.height) == ("H264", 1280, 720)
+    assert info.profile_level_id == "64001f"
+    assert info.to_dict()["sps"] == "Z2QAH6zZQFAFuwEQAAADABAAAAMDIPGDGWA="
+
+
+def test_truncated_sps_falls_back_to_the_sdp_size():
+    info = stream_info_from_parameter_sets("h264", {7: HIGH_720P_SPS[:6], 8: PPS}, width=1280, height=720)
+
+    assert (info.width, info.height) == (1280, 720)
+    assert info.profile_level_id is None
+
+
+def test_stream_info_needs_both_sps_and_pps():
+    assert stream_info_from_parameter_sets("h264", {7: HIGH_720P_SPS}, width=1280, height=720) is None